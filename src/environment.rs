@@ -1,15 +1,91 @@
-use crate::grid::Grid;
+use std::collections::HashMap;
+
+use crate::{error::{CellError, ErrorCell}, functions::FunctionRegistry, grid::{Grid, Grids}, model::{CellAddress, Evaluatable, Primitive, SheetId}};
+
+/// Most calls of lambdas and workbook names that can be nested inside each other
+pub const MAX_CALL_DEPTH: usize = 64;
+
+/// Formulas defined in the workbook under a name, keyed by lowercase name
+pub type NameTable = HashMap<String, Box<dyn Evaluatable>>;
 
 pub struct Environment<'a> {
     grid: &'a Grid,
+    /// Grids of every sheet of the workbook, for reading cells on other sheets
+    grids: Option<&'a Grids>,
+    functions: &'a FunctionRegistry,
+    names: &'a NameTable,
+    /// Values bound to names by the enclosing let expressions and lambdas, keyed by lowercase name
+    variables: HashMap<String, Primitive>,
+    /// Number of lambda calls and workbook names being evaluated
+    depth: usize,
 }
 
-impl Environment<'_> {
-    pub fn new<'a>(grid: &'a Grid) -> Environment {
-        Environment { grid }
+impl<'a> Environment<'a> {
+    pub fn new(grid: &'a Grid, functions: &'a FunctionRegistry, names: &'a NameTable) -> Environment<'a> {
+        Environment { grid, grids: None, functions, names, variables: HashMap::new(), depth: 0 }
     }
-    
+
+    /// Environment for evaluating a formula on the given sheet of a workbook, which can also read
+    /// cells on the other sheets
+    pub fn for_sheet(grids: &'a Grids, sheet: SheetId, functions: &'a FunctionRegistry, names: &'a NameTable) -> Environment<'a> {
+        Environment { grid: &grids[&sheet], grids: Some(grids), functions, names, variables: HashMap::new(), depth: 0 }
+    }
+
     pub fn grid(&self) -> &Grid {
         self.grid
     }
+
+    /// Cell of the grid being read, as named in errors read from it
+    pub fn error_cell(&self, adr: CellAddress) -> ErrorCell {
+        ErrorCell { sheet: self.grid.name().map(String::from), adr }
+    }
+
+    pub fn functions(&self) -> &FunctionRegistry {
+        self.functions
+    }
+
+    pub fn variables(&self) -> &HashMap<String, Primitive> {
+        &self.variables
+    }
+
+    /// Value bound to the given name, regardless of case
+    pub fn variable(&self, name: &str) -> Option<&Primitive> {
+        self.variables.get(&name.to_lowercase())
+    }
+
+    /// Value of the given name, regardless of case, which is either a variable in scope or a formula
+    /// defined in the workbook
+    pub fn lookup(&self, name: &str) -> Option<Result<Primitive, CellError>> {
+        if let Some(val) = self.variable(name) {
+            return Some(Ok(val.clone()));
+        }
+
+        let expression = self.names.get(&name.to_lowercase())?;
+        Some(self.call_scope(HashMap::new()).and_then(|scope| expression.evaluate(&scope)))
+    }
+
+    /// Environment in which the given name is bound to a value, along with every name already bound
+    /// in this one except for one of the same name
+    pub fn with_variable(&self, name: &str, val: Primitive) -> Environment<'a> {
+        let mut variables = self.variables.clone();
+        variables.insert(name.to_lowercase(), val);
+        Environment { grid: self.grid, grids: self.grids, functions: self.functions, names: self.names, variables, depth: self.depth }
+    }
+
+    /// Environment that reads cells from the given sheet, with the same names bound as in this one
+    pub fn on_sheet(&self, sheet: SheetId) -> Result<Environment<'a>, CellError> {
+        match self.grids.and_then(|grids| grids.get(&sheet)) {
+            Some(grid) => Ok(Environment { grid, grids: self.grids, functions: self.functions, names: self.names, variables: self.variables.clone(), depth: self.depth }),
+            None => Err(CellError::Ref(String::from("Reference to a sheet that does not exist"))),
+        }
+    }
+
+    /// Environment for evaluating the body of a lambda or a workbook name, in which only the given
+    /// variables are bound. Fails once calls are nested too deeply, such as in endless recursion
+    pub fn call_scope(&self, variables: HashMap<String, Primitive>) -> Result<Environment<'a>, CellError> {
+        if self.depth >= MAX_CALL_DEPTH {
+            return Err(CellError::RecursionLimit(MAX_CALL_DEPTH));
+        }
+        Ok(Environment { grid: self.grid, grids: self.grids, functions: self.functions, names: self.names, variables, depth: self.depth + 1 })
+    }
 }
@@ -0,0 +1,119 @@
+use std::fmt;
+
+use crate::model::CellAddress;
+
+/// Cell named in an error, with the name of its sheet when it is on a sheet of a workbook
+#[derive(Clone, Debug, PartialEq)]
+pub struct ErrorCell {
+    pub sheet: Option<String>,
+    pub adr: CellAddress,
+}
+
+impl fmt::Display for ErrorCell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.sheet {
+            Some(sheet) => write!(f, "{sheet}!{}", self.adr.to_string()),
+            None => write!(f, "{}", self.adr.to_string()),
+        }
+    }
+}
+
+/// Reason a cell could not produce a value
+#[derive(Clone, Debug, PartialEq)]
+pub enum CellError {
+    /// Division by zero
+    DivZero,
+    /// Reference to cells that cannot be read, such as a backwards range
+    Ref(String),
+    /// Operand of the wrong type for an operation
+    Value(String),
+    /// Formula that could not be lexed or parsed, with the span of characters at fault
+    Parse { message: String, start: usize, end: usize },
+    /// Cell that takes part in a circular reference, with the cells that make up the cycle
+    Circular(Vec<ErrorCell>),
+    /// Calculation with no defined result, such as 0 ** 0
+    Num(String),
+    /// Integer calculation whose result does not fit in 64 bits
+    Overflow,
+    /// Call of a function that does not exist
+    Name(String),
+    /// Lambdas or workbook names nested deeper than the given number of calls
+    RecursionLimit(usize),
+    /// Array result that cannot be written into the cells next to its formula, with the first cell
+    /// in the way
+    Spill(CellAddress),
+    /// Array results that kept moving each other after the given number of recalculations
+    UnsettledSpill(usize),
+    /// Shift by a negative number of bits or by at least the width of an integer
+    InvalidShift(i64),
+    /// Error read from another cell, with the cell it originated in and every cell it was read
+    /// through on the way, starting with the origin
+    Propagated { error: Box<CellError>, origin: ErrorCell, chain: Vec<ErrorCell> },
+}
+
+impl CellError {
+    /// Spreadsheet-style code shown in place of the value of a cell
+    pub fn short_code(&self) -> &'static str {
+        match self {
+            Self::DivZero => "#DIV/0!",
+            Self::Ref(_) => "#REF!",
+            Self::Value(_) => "#VALUE!",
+            Self::Parse { .. } => "#ERROR!",
+            Self::Circular(_) => "#CIRC!",
+            Self::Name(_) => "#NAME?",
+            Self::Spill(_) | Self::UnsettledSpill(_) => "#SPILL!",
+            Self::RecursionLimit(_) => "#NUM!",
+            Self::Num(_) | Self::Overflow | Self::InvalidShift(_) => "#NUM!",
+            Self::Propagated { error, .. } => error.short_code(),
+        }
+    }
+
+    /// Records that the error was read from the given cell by a reference to it
+    pub fn propagate(self, cell: ErrorCell) -> CellError {
+        match self {
+            Self::Propagated { error, origin, mut chain } => {
+                chain.push(cell);
+                Self::Propagated { error, origin, chain }
+            }
+            error => Self::Propagated { error: Box::new(error), origin: cell.clone(), chain: vec![cell] },
+        }
+    }
+
+    /// Cell the error originated in, if it was read from another cell
+    pub fn origin(&self) -> Option<&ErrorCell> {
+        match self {
+            Self::Propagated { origin, .. } => Some(origin),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::DivZero => write!(f, "Division by 0"),
+            Self::Ref(message) => write!(f, "{message}"),
+            Self::Value(message) => write!(f, "{message}"),
+            Self::Parse { message, start, .. } => write!(f, "{message} at index {start}"),
+            Self::Circular(cycle) => {
+                let path: Vec<String> = cycle.iter().map(|adr| adr.to_string()).collect();
+                write!(f, "Circular reference: {}", path.join(" -> "))
+            }
+            Self::Num(message) => write!(f, "{message}"),
+            Self::Overflow => write!(f, "Integer overflow"),
+            Self::Name(message) => write!(f, "{message}"),
+            Self::RecursionLimit(depth) => write!(f, "Calls nested deeper than {depth}"),
+            Self::Spill(adr) => write!(f, "Spill blocked by cell {}", adr.to_string()),
+            Self::UnsettledSpill(passes) => write!(f, "Spilled arrays did not settle after {passes} recalculations"),
+            Self::InvalidShift(bits) => write!(f, "Invalid shift by {bits} bits"),
+            Self::Propagated { error, origin, chain } => {
+                if chain.len() > 1 {
+                    let path: Vec<String> = chain[1..].iter().rev().map(|adr| adr.to_string()).collect();
+                    write!(f, "{error} (from {origin} via {})", path.join(" -> "))
+                } else {
+                    write!(f, "{error} (from {origin})")
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{error::CellError, lexer::{lex, TokenType}, model::{CellAddress, Primitive}, parser::SPECIAL_FORMS};
+
+/// Kind of argument a function parameter accepts, which decides how the argument is parsed and
+/// which values it is checked against before the function is called
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArgumentKind {
+    /// Any expression, evaluated to a single value of any type
+    Value,
+    /// Expression evaluating to an integer or a float
+    Number,
+    /// Expression evaluating to an integer
+    Integer,
+    /// Expression evaluating to a boolean
+    Boolean,
+    /// Expression evaluating to a string
+    String,
+    /// Array of values such as a block of cells, flattened row by row. A block of cells can also be
+    /// written as [x, y], [x, y], as in older formulas
+    Range,
+}
+
+impl fmt::Display for ArgumentKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Value => write!(f, "Value"),
+            Self::Number => write!(f, "Number"),
+            Self::Integer => write!(f, "Integer"),
+            Self::Boolean => write!(f, "Boolean"),
+            Self::String => write!(f, "String"),
+            Self::Range => write!(f, "Range"),
+        }
+    }
+}
+
+impl ArgumentKind {
+    /// Checks an argument against the kind, turning an empty value into the zero value of the kind
+    fn check(&self, argument: &ArgumentValue) -> Result<ArgumentValue, String> {
+        let val = match (self, argument) {
+            (Self::Range, ArgumentValue::Range(_)) => return Ok(argument.clone()),
+            (Self::Range, ArgumentValue::Value(Primitive::Array(rows))) => return Ok(ArgumentValue::Range(rows.concat())),
+            // A single value is a range of one cell
+            (Self::Range, ArgumentValue::Value(val)) => return Ok(ArgumentValue::Range(vec![val.clone()])),
+            (_, ArgumentValue::Range(_)) => return Err(String::from("Range")),
+            (_, ArgumentValue::Value(val)) => val,
+        };
+
+        let checked = match (self, val) {
+            (Self::Value, val) => val.clone(),
+            (Self::Number | Self::Integer, Primitive::Empty) => Primitive::Integer(0),
+            (Self::Number, Primitive::Integer(_) | Primitive::Float(_)) => val.clone(),
+            (Self::Integer, Primitive::Integer(_)) => val.clone(),
+            (Self::Boolean, Primitive::Empty) => Primitive::Boolean(false),
+            (Self::Boolean, Primitive::Boolean(_)) => val.clone(),
+            (Self::String, Primitive::Empty) => Primitive::String(String::new()),
+            (Self::String, Primitive::String(_)) => val.clone(),
+            (_, val) => return Err(val.type_string()),
+        };
+        Ok(ArgumentValue::Value(checked))
+    }
+}
+
+/// Evaluated argument handed to the implementation of a function
+#[derive(Clone, Debug)]
+pub enum ArgumentValue {
+    Value(Primitive),
+    /// Values of the cells in a range, row by row
+    Range(Vec<Primitive>),
+}
+
+/// Code run when a function is called, given arguments that have already been checked against the
+/// parameters of the function
+pub type Implementation = Box<dyn Fn(&[ArgumentValue]) -> Result<Primitive, CellError>>;
+
+/// Function that can be called from a formula
+pub struct Function {
+    name: String,
+    parameters: Vec<ArgumentKind>,
+    /// Whether the last parameter can be repeated any number of times
+    variadic: bool,
+    implementation: Implementation,
+}
+
+impl Function {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Kind of the argument at the given position
+    pub fn parameter_kind(&self, index: usize) -> ArgumentKind {
+        match self.parameters.get(index) {
+            Some(kind) => *kind,
+            None => *self.parameters.last().unwrap_or(&ArgumentKind::Value),
+        }
+    }
+
+    /// Describes why the function cannot be called with the given number of arguments, if it cannot
+    pub fn arity_error(&self, count: usize) -> Option<String> {
+        if self.variadic && count < self.parameters.len() {
+            Some(format!("Function {} expects at least {} argument(s) but got {}", self.name, self.parameters.len(), count))
+        } else if !self.variadic && count != self.parameters.len() {
+            Some(format!("Function {} expects {} argument(s) but got {}", self.name, self.parameters.len(), count))
+        } else {
+            None
+        }
+    }
+
+    /// Checks the arguments against the parameters of the function and calls it
+    pub fn call(&self, arguments: &[ArgumentValue]) -> Result<Primitive, CellError> {
+        if let Some(message) = self.arity_error(arguments.len()) {
+            return Err(CellError::Value(message));
+        }
+
+        let mut checked: Vec<ArgumentValue> = Vec::new();
+        for (index, argument) in arguments.iter().enumerate() {
+            let kind = self.parameter_kind(index);
+            match kind.check(argument) {
+                Ok(val) => checked.push(val),
+                Err(type_string) => return Err(CellError::Value(format!("Argument {} of {} must be {} but got {}", index + 1, self.name, kind, type_string))),
+            }
+        }
+
+        (self.implementation)(&checked)
+    }
+}
+
+/// Functions that can be called from formulas, looked up by name regardless of case
+pub struct FunctionRegistry {
+    functions: HashMap<String, Function>,
+}
+
+impl FunctionRegistry {
+    /// Registry holding the built-in functions
+    pub fn new() -> FunctionRegistry {
+        let mut registry = FunctionRegistry { functions: HashMap::new() };
+        registry.insert("max", vec![ArgumentKind::Range], true, max);
+        registry.insert("mean", vec![ArgumentKind::Range], true, mean);
+        registry.insert("min", vec![ArgumentKind::Range], true, min);
+        registry.insert("sum", vec![ArgumentKind::Range], true, sum);
+        registry
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Function> {
+        self.functions.get(&name.to_lowercase())
+    }
+
+    /// Adds a function, replacing any function of the same name. Arguments are checked against the
+    /// parameters before the implementation is called, so it only ever sees values of the declared
+    /// kinds, and a variadic function takes any number of arguments of its last parameter's kind.
+    /// The names of the special forms, such as if and let, cannot be used as they are never looked
+    /// up in the registry, nor can names that are not identifiers or read as a cell in any case
+    pub fn register<F>(&mut self, name: &str, parameters: Vec<ArgumentKind>, variadic: bool, implementation: F) -> Result<(), CellError>
+    where
+        F: Fn(&[ArgumentValue]) -> Result<Primitive, CellError> + 'static,
+    {
+        if SPECIAL_FORMS.contains(&name.to_lowercase().as_str()) {
+            return Err(CellError::Name(format!("{name} is a special form and cannot be registered as a function")));
+        }
+        let is_identifier = matches!(lex(name).as_deref(), Ok([token]) if token.token_type == TokenType::Identifier);
+        if !is_identifier || CellAddress::from_a1(&name.to_uppercase()).is_some() {
+            return Err(CellError::Name(format!("Invalid function name {name}")));
+        }
+        self.insert(name, parameters, variadic, implementation);
+        Ok(())
+    }
+
+    fn insert<F>(&mut self, name: &str, parameters: Vec<ArgumentKind>, variadic: bool, implementation: F)
+    where
+        F: Fn(&[ArgumentValue]) -> Result<Primitive, CellError> + 'static,
+    {
+        let function = Function { name: name.to_string(), parameters, variadic, implementation: Box::new(implementation) };
+        self.functions.insert(name.to_lowercase(), function);
+    }
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Numeric values among the arguments, skipping empty cells
+fn numeric_values(arguments: &[ArgumentValue]) -> Result<Vec<Primitive>, CellError> {
+    let mut values: Vec<Primitive> = Vec::new();
+
+    for argument in arguments {
+        let argument_values = match argument {
+            ArgumentValue::Value(val) => std::slice::from_ref(val),
+            ArgumentValue::Range(vals) => vals.as_slice(),
+        };
+
+        for val in argument_values {
+            match val {
+                Primitive::Integer(_) | Primitive::Float(_) => values.push(val.clone()),
+                Primitive::Empty => (),
+                _ => return Err(CellError::Value(format!("Value {} is not numeric", val.to_string()))),
+            }
+        }
+    }
+
+    Ok(values)
+}
+
+fn float_value(val: &Primitive) -> f64 {
+    match val {
+        Primitive::Integer(val) => *val as f64,
+        Primitive::Float(val) => *val,
+        _ => panic!("Unexpected non-numeric value"),
+    }
+}
+
+/// Sum of the values, starting from 0.0 as f64's own sum starts from -0.0
+fn total(values: &[Primitive]) -> f64 {
+    values.iter().fold(0.0, |total, val| total + float_value(val))
+}
+
+fn sum(arguments: &[ArgumentValue]) -> Result<Primitive, CellError> {
+    let values = numeric_values(arguments)?;
+    Ok(Primitive::Float(total(&values)))
+}
+
+fn mean(arguments: &[ArgumentValue]) -> Result<Primitive, CellError> {
+    let values = numeric_values(arguments)?;
+    if values.is_empty() {
+        return Err(CellError::DivZero);
+    }
+    Ok(Primitive::Float(total(&values) / values.len() as f64))
+}
+
+fn max(arguments: &[ArgumentValue]) -> Result<Primitive, CellError> {
+    let values = numeric_values(arguments)?;
+    let mut max_val = Primitive::Integer(0);
+    for (index, val) in values.iter().enumerate() {
+        if index == 0 || float_value(val) > float_value(&max_val) {
+            max_val = val.clone();
+        }
+    }
+    Ok(max_val)
+}
+
+fn min(arguments: &[ArgumentValue]) -> Result<Primitive, CellError> {
+    let values = numeric_values(arguments)?;
+    let mut min_val = Primitive::Integer(0);
+    for (index, val) in values.iter().enumerate() {
+        if index == 0 || float_value(val) < float_value(&min_val) {
+            min_val = val.clone();
+        }
+    }
+    Ok(min_val)
+}
@@ -1,102 +1,65 @@
-use crate::{model::{Evaluatable, CellAddress, Primitive}, lexer::lex, parser::parse, environment::Environment};
+use crate::{model::{Evaluatable, CellAddress, Primitive, SheetId}, error::CellError, functions::FunctionRegistry, lexer::lex, parser::{parse, NamedRanges, SheetNames}, environment::{Environment, NameTable}};
 use std::collections::HashMap;
 
 pub struct Grid {
-    map: HashMap<CellAddress, Result<Primitive, String>>,
+    map: HashMap<CellAddress, Result<Primitive, CellError>>,
+    /// Name of the sheet whose values the grid holds, when it is part of a workbook
+    name: Option<String>,
 } 
 
 impl Grid {
     pub fn new() -> Grid {
-        Grid { map: HashMap::new(), }
-    }
-    
-    pub fn set_cell(&mut self, adr: &CellAddress, val: Result<Primitive, String>) {
-       self.map.insert(*adr, val);
+        Grid { map: HashMap::new(), name: None }
     }
 
-    pub fn get_cell(&self, adr: &CellAddress) -> Option<&Result<Primitive, String>> {
-        self.map.get(&adr)
+    /// Empty grid for the values of the sheet of a workbook with the given name
+    pub fn for_sheet(name: &str) -> Grid {
+        Grid { map: HashMap::new(), name: Some(name.to_string()) }
     }
-}
 
-pub struct TextGrid {
-    grid: Grid,
-    map: HashMap<CellAddress, String>,
-    dimensions: (usize, usize),
-}
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
 
-impl TextGrid {
-    pub fn new(dimensions: (usize, usize)) -> TextGrid {
-        TextGrid {
-            grid: Grid::new(),
-            map: HashMap::new(),
-            dimensions,
-        }
+    pub fn set_name(&mut self, name: &str) {
+        self.name = Some(name.to_string());
     }
 
-    pub fn get_cell_text(&self, adr: CellAddress) -> Option<&String> {
-        self.map.get(&adr)
+    /// Removes the values of every cell
+    pub fn clear(&mut self) {
+        self.map.clear();
     }
     
-    pub fn set_cell_text(&mut self, adr: CellAddress, str: String) {
-        self.map.insert(adr, str);
-        self.update_cells();
+    pub fn set_cell(&mut self, adr: &CellAddress, val: Result<Primitive, CellError>) {
+       self.map.insert(*adr, val);
     }
 
-    fn update_cells(&mut self) {
-        for i in 0..self.dimensions.0 {
-            for j in 0..self.dimensions.1 {
-                self.evaluate_cell(CellAddress(i as i32, j as i32));
-            }
-        }
+    pub fn get_cell(&self, adr: &CellAddress) -> Option<&Result<Primitive, CellError>> {
+        self.map.get(&adr)
     }
 
-    pub fn get_cell_value(&self, adr: CellAddress) -> Option<&Result<Primitive, String>> {
-        self.grid.get_cell(&adr)
+    pub fn remove_cell(&mut self, adr: &CellAddress) {
+        self.map.remove(adr);
     }
 
-    fn evaluate_cell(&mut self, adr: CellAddress) {
-        let result = self.map.get(&adr);
-        match result {
-            Some(text) => {
-                if text.len() != 0 && text.chars().next().unwrap() == '=' {
-                    let new_text = &text[1..text.len()].chars().fold("".to_string(), |mut acc, character| { acc.push(character); acc });
-                    self.grid.set_cell(&adr, evaluate_from_string(&new_text, &self.grid));
-                } else if text.parse::<i32>().is_ok() {
-                    self.grid.set_cell(&adr, Ok(Primitive::Integer(text.parse::<i32>().unwrap())));
-                } else if text.parse::<bool>().is_ok() {
-                    self.grid.set_cell(&adr, Ok(Primitive::Boolean(text.parse::<bool>().unwrap())));
-                } else if text.parse::<f64>().is_ok() {
-                    self.grid.set_cell(&adr, Ok(Primitive::Float(text.parse::<f32>().unwrap())));
-                } else if !text.is_empty() {
-                    self.grid.set_cell(&adr, Ok(Primitive::String(text.to_string())));
-                } else {
-                    self.grid.map.remove(&adr);
-                }
-            }
-            None => (),
-        }
+    pub fn get_all_cells(&self) -> Vec<(&CellAddress, &Result<Primitive, CellError>)> {
+        self.map.iter().collect()
     }
+}
 
-    // fn get_grid(&self) -> &Grid {
-    //     &self.grid
-    // }
+/// Values of the cells of each sheet of a workbook
+pub type Grids = HashMap<SheetId, Grid>;
 
-    pub fn get_all_cell_values(&self) -> Vec<(&CellAddress, &Result<Primitive, String>)> {
-        self.grid.map.iter().collect()
+pub fn evaluate_from_string(str: &str, grid: &Grid, functions: &FunctionRegistry) -> Result<Primitive, CellError> {
+    match parse_from_string(str, functions, &NamedRanges::new(), &SheetNames::new()) {
+        Ok(expression) => expression.evaluate(&Environment::new(grid, functions, &NameTable::new())),
+        Err(string) => Err(string),
     }
 }
 
-pub fn evaluate_from_string(str: &String, grid: &Grid) -> Result<Primitive, String> {
+pub fn parse_from_string(str: &str, functions: &FunctionRegistry, ranges: &NamedRanges, sheets: &SheetNames) -> Result<Box<dyn Evaluatable>, CellError> {
     match lex(str) {
-        Ok(tokens) => {
-            match parse(tokens) {
-                Ok(expression) => {
-                    expression.evaluate(&Environment::new(grid))
-                }
-                Err(string) => Err(string),
-            }
-        }
+        Ok(tokens) => parse(tokens, functions, ranges, sheets),
         Err(string) => Err(string),
     }
 }
@@ -1,25 +1,48 @@
 use ncurses::{*, ll::curs_set};
-use std::cmp;
+use std::{cmp, collections::HashMap};
 
-use crate::{grid::TextGrid, model::{CellAddress, Primitive}};
+use crate::{workbook::{literal_text, SheetAddress, Workbook}, error::CellError, model::{column_name, CellAddress, CellRange, Primitive, SheetId}};
 
 const CELL_WIDTH: i32 = 7;
 const CELL_HEIGHT: i32 = 1;
 const CELL_HORIZ_OFFSET: i32 = 3;
 const CELL_VERT_OFFSET: i32 = 1;
 
+/// Grid cursor and scroll offset of a sheet
+type Position = ((i32, i32), (i32, i32));
+
 pub struct Interface {
-    grid_dimensions: (i32, i32),
     grid_window: WINDOW,
+    /// Line of sheet tabs between the grid and the editor
+    tab_window: WINDOW,
     editor_window: WINDOW,
     result_window: WINDOW,
     mode: Mode,
     text: String,
+    /// Sheet shown in the grid window
+    sheet: SheetId,
     grid_cursor: (i32, i32),
+    /// Row and column of the top left cell shown in the grid window
+    scroll_offset: (i32, i32),
+    /// Cursor and scroll offset of each sheet that has been switched away from
+    positions: HashMap<SheetId, Position>,
+    /// (row, column) where the selection started, the selection spanning from there to the cursor
+    selection: Option<(i32, i32)>,
+    clipboard: Option<Clipboard>,
+}
+
+/// Cells copied or cut from a sheet, to be pasted elsewhere
+struct Clipboard {
+    sheet: SheetId,
+    range: CellRange,
+    /// Text and value of each copied cell, the text being empty for spilled cells
+    cells: HashMap<CellAddress, (String, Option<Primitive>)>,
+    /// Whether the cells are moved once pasted, in which case references to them follow them
+    cut: bool,
 }
 
 impl Interface {
-    pub fn new(grid_dimensions: (i32, i32)) -> Self {
+    pub fn new() -> Self {
         initscr();
         refresh();
         noecho();
@@ -30,30 +53,39 @@ impl Interface {
         let mut height: i32 = 0;
         getmaxyx(stdscr(), &mut height, &mut width);
 
-        let grid_window = newwin(height - 4, width, 0, 0);
+        let grid_window = newwin(height - 5, width, 0, 0);
+        let tab_window = newwin(1, width, height - 5, 0);
         let editor_window = newwin(2, width, height - 4, 0);
         let result_window = newwin(2, width, height - 2, 0);
         wrefresh(grid_window);
+        wrefresh(tab_window);
         wrefresh(editor_window);
         wrefresh(result_window);
         
         Self {
-            grid_dimensions,
             grid_window,
+            tab_window,
             editor_window,
             result_window,
             mode: Mode::Grid,
             text: String::new(),
+            sheet: 0,
             grid_cursor: (0, 0),
+            scroll_offset: (0, 0),
+            positions: HashMap::new(),
+            selection: None,
+            clipboard: None,
         }
     }
 
-    pub fn setup(&mut self) {
+    pub fn setup(&mut self, workbook: &Workbook) {
+        self.sheet = workbook.get_sheets()[0].0;
         let mut height = 0;
         let mut width = 0;
         getmaxyx(self.grid_window, &mut height, &mut width);
 
-        self.draw_grid();
+        self.update_grid(workbook);
+        self.draw_tabs(workbook);
 
         wmove(self.editor_window, 0, 0);
         whline(self.editor_window, ACS_HLINE(), width);
@@ -62,147 +94,148 @@ impl Interface {
         wmove(self.result_window, 0, 0);
         whline(self.result_window, ACS_HLINE(), width);
         wrefresh(self.result_window);
-        mv(self.grid_cursor.0 * (CELL_HEIGHT + 1) + CELL_VERT_OFFSET + 1, self.grid_cursor.1 * (CELL_WIDTH + 1) + CELL_HORIZ_OFFSET + 1);
+        let (cursor_y, cursor_x) = self.cell_position(self.grid_cursor);
+        mv(cursor_y, cursor_x);
     }
 
-    fn draw_grid(&self) {
+    /// Number of rows and columns of cells that fit in the grid window
+    fn visible_cells(&self) -> (i32, i32) {
+        let mut grid_window_height = 0;
+        let mut grid_window_width = 0;
+        getmaxyx(self.grid_window, &mut grid_window_height, &mut grid_window_width);
+
+        (
+            cmp::max(1, (grid_window_height - CELL_VERT_OFFSET - 1) / (CELL_HEIGHT + 1)),
+            cmp::max(1, (grid_window_width - self.row_header_width() - 1) / (CELL_WIDTH + 1)),
+        )
+    }
+
+    /// Width of the row numbers left of the grid
+    fn row_header_width(&self) -> i32 {
         let mut grid_window_height = 0;
         let mut grid_window_width = 0;
         getmaxyx(self.grid_window, &mut grid_window_height, &mut grid_window_width);
 
-        let (num_grid_rows, num_grid_cols) = self.grid_dimensions;
+        let last_row = self.scroll_offset.0.saturating_add((grid_window_height - CELL_VERT_OFFSET - 1) / (CELL_HEIGHT + 1));
+        cmp::max(CELL_HORIZ_OFFSET, last_row.to_string().len() as i32 + 1)
+    }
+
+    /// Position in the grid window of the first character of the cell at the given (row, column)
+    fn cell_position(&self, cell: (i32, i32)) -> (i32, i32) {
+        (
+            (cell.0 - self.scroll_offset.0) * (CELL_HEIGHT + 1) + CELL_VERT_OFFSET + 1,
+            (cell.1 - self.scroll_offset.1) * (CELL_WIDTH + 1) + self.row_header_width() + 1,
+        )
+    }
+
+    fn is_visible(&self, cell: (i32, i32)) -> bool {
+        let (num_rows, num_cols) = self.visible_cells();
+        cell.0 >= self.scroll_offset.0 && cell.0 - self.scroll_offset.0 < num_rows
+            && cell.1 >= self.scroll_offset.1 && cell.1 - self.scroll_offset.1 < num_cols
+    }
+
+    fn draw_grid(&self) {
+        let (num_grid_rows, num_grid_cols) = self.visible_cells();
+        let header_width = self.row_header_width();
         
-        let grid_char_width = 1 + (1 + CELL_WIDTH) * num_grid_cols as i32;
-        let grid_char_height = 1 + (1 + CELL_HEIGHT) * num_grid_rows as i32;
+        let grid_char_width = 1 + (1 + CELL_WIDTH) * num_grid_cols;
+        let grid_char_height = 1 + (1 + CELL_HEIGHT) * num_grid_rows;
 
-        wmove(self.grid_window, CELL_VERT_OFFSET, CELL_HORIZ_OFFSET);
+        wmove(self.grid_window, CELL_VERT_OFFSET, header_width);
         wvline(self.grid_window, ACS_VLINE(), grid_char_height);
-        for col in 0..num_grid_cols as i32 {
-            mvwaddstr(self.grid_window, 0, col as i32 * (CELL_WIDTH + 1) + 1 + CELL_HORIZ_OFFSET, &col.to_string()); 
-            wmove(self.grid_window, CELL_VERT_OFFSET, (1 + CELL_WIDTH) * (col + 1) + CELL_HORIZ_OFFSET);
+        for col in 0..num_grid_cols {
+            let header = self.scroll_offset.1.checked_add(col).map(column_name).unwrap_or_default();
+            mvwaddstr(self.grid_window, 0, col * (CELL_WIDTH + 1) + 1 + header_width, &format!("{0:.1$}", header, CELL_WIDTH as usize));
+            wmove(self.grid_window, CELL_VERT_OFFSET, (1 + CELL_WIDTH) * (col + 1) + header_width);
             wvline(self.grid_window, ACS_VLINE(), grid_char_height);
         }
 
-        wmove(self.grid_window, CELL_VERT_OFFSET, CELL_HORIZ_OFFSET);
+        wmove(self.grid_window, CELL_VERT_OFFSET, header_width);
         whline(self.grid_window, ACS_HLINE(), grid_char_width);
-        for row in 0..num_grid_rows as i32 {
-            mvwaddstr(self.grid_window, row as i32 * (CELL_HEIGHT + 1) + 1 + CELL_VERT_OFFSET, 0, &row.to_string()); 
-            wmove(self.grid_window, (1 + CELL_HEIGHT) * (row + 1) + CELL_VERT_OFFSET, CELL_HORIZ_OFFSET);
+        for row in 0..num_grid_rows {
+            let header = self.scroll_offset.0.checked_add(row + 1).map(|row| row.to_string()).unwrap_or_default();
+            mvwaddstr(self.grid_window, row * (CELL_HEIGHT + 1) + 1 + CELL_VERT_OFFSET, 0, &header);
+            wmove(self.grid_window, (1 + CELL_HEIGHT) * (row + 1) + CELL_VERT_OFFSET, header_width);
             whline(self.grid_window, ACS_HLINE(), grid_char_width);
         }
 
         wrefresh(self.grid_window);
     }
 
-    pub fn update(&mut self, grid: &mut TextGrid) -> bool {
+    pub fn update(&mut self, workbook: &mut Workbook) -> bool {
         let mut result = true;
-        wclear(self.grid_window);
-        self.update_grid(grid.get_all_cell_values());
         match self.mode {
             Mode::Grid => {
-                wmove(self.grid_window, self.grid_cursor.0 * (CELL_HEIGHT + 1) + CELL_VERT_OFFSET + 1, self.grid_cursor.1 * (CELL_WIDTH + 1) + CELL_HORIZ_OFFSET + 1);
+                let (cursor_y, cursor_x) = self.cell_position(self.grid_cursor);
+                wmove(self.grid_window, cursor_y, cursor_x);
                 let key = getch();
                 if key == 'q' as i32 {
                     result = false;
                 } else if key == '\n' as i32 {
-                    wclear(self.grid_window);
                     wmove(self.editor_window, 1, 0);
                     waddstr(self.editor_window, &self.text);
                     wrefresh(self.editor_window);
                     self.mode = Mode::Editor;
-                } else if key == KEY_UP {
-                    if self.grid_cursor.0 == 0 {
-                        self.grid_cursor.0 = self.grid_dimensions.0 - 1;
-                    } else {
-                        self.grid_cursor.0 = (self.grid_cursor.0 - 1) % self.grid_dimensions.0;
-                    }
-                    mv(self.grid_cursor.0 * (CELL_HEIGHT + 1) + CELL_VERT_OFFSET + 1, self.grid_cursor.1 * (CELL_WIDTH + 1) + CELL_HORIZ_OFFSET + 1);
-                    self.text = match grid.get_cell_text(cursor_pos_to_cell_address(self.grid_cursor)) {
-                        Some(val) => val.to_owned(),
-                        None => "".to_string(),
+                } else if key == ':' as i32 {
+                    self.start_command("");
+                } else if key == '+' as i32 {
+                    self.start_command("add sheet ");
+                } else if key == 'R' as i32 {
+                    self.start_command("rename sheet ");
+                } else if key == 'X' as i32 {
+                    self.start_command("delete sheet");
+                } else if key == ']' as i32 || key == '[' as i32 {
+                    let sheets = workbook.get_sheets();
+                    let index = sheets.iter().position(|(sheet, _)| *sheet == self.sheet).unwrap_or(0);
+                    let index = if key == ']' as i32 { (index + 1) % sheets.len() } else { (index + sheets.len() - 1) % sheets.len() };
+                    let sheet = sheets[index].0;
+                    self.switch_sheet(workbook, sheet);
+                } else if key == '}' as i32 || key == '{' as i32 {
+                    let index = workbook.get_sheets().iter().position(|(sheet, _)| *sheet == self.sheet).unwrap_or(0);
+                    let index = if key == '}' as i32 { index + 1 } else { index.saturating_sub(1) };
+                    let _ = workbook.move_sheet(self.sheet, index);
+                    self.draw_tabs(workbook);
+                    mv(cursor_y, cursor_x);
+                } else if key == 'v' as i32 {
+                    self.selection = match self.selection {
+                        Some(_) => None,
+                        None => Some(self.grid_cursor),
                     };
-
-
-                    wmove(self.editor_window, 1, 0);
-                    wclrtoeol(self.editor_window);
-                    waddstr(self.editor_window, &self.text);
-                    wrefresh(self.editor_window);
-                    self.set_result("");
-
-                    if let Some(result) = grid.get_cell_value(cursor_pos_to_cell_address(self.grid_cursor)) {
-                        match result {
-                            Ok(val) => self.set_result(&val.to_string()),
-                            Err(err) => self.set_result(&err),
-                        }
-                    }
-               } else if key == KEY_DOWN {
-                    self.grid_cursor.0 = (self.grid_cursor.0 + 1) % self.grid_dimensions.0;
-                    mv(self.grid_cursor.0 * (CELL_HEIGHT + 1) + CELL_VERT_OFFSET + 1, self.grid_cursor.1 * (CELL_WIDTH + 1) + CELL_HORIZ_OFFSET + 1);
-                    self.text = match grid.get_cell_text(cursor_pos_to_cell_address(self.grid_cursor)) {
-                        Some(val) => val.to_owned(),
-                        None => "".to_string(),
+                    self.update_grid(workbook);
+                } else if key == 'c' as i32 || key == 'x' as i32 {
+                    let message = self.copy(workbook, key == 'x' as i32);
+                    self.update_grid(workbook);
+                    self.set_result(&message);
+                } else if key == 'p' as i32 || key == 'P' as i32 {
+                    let message = self.paste(workbook, key == 'P' as i32);
+                    self.move_cursor(workbook, self.grid_cursor);
+                    self.set_result(&message);
+                } else if key == 'g' as i32 {
+                    let origin = match workbook.get_cell_value(self.sheet, cursor_pos_to_cell_address(self.grid_cursor)) {
+                        Some(Err(err)) => err.origin().cloned(),
+                        _ => None,
                     };
-                    wmove(self.editor_window, 1, 0);
-                    wclrtoeol(self.editor_window);
-                    waddstr(self.editor_window, &self.text);
-                    wrefresh(self.editor_window);
-                    self.set_result("");
-                    
-                     if let Some(result) = grid.get_cell_value(cursor_pos_to_cell_address(self.grid_cursor)) {
-                        match result {
-                            Ok(val) => self.set_result(&val.to_string()),
-                            Err(err) => self.set_result(&err),
+                    if let Some(origin) = origin.filter(|origin| origin.adr.0 >= 0 && origin.adr.1 >= 0) {
+                        if let Some(sheet) = origin.sheet.and_then(|name| workbook.get_sheet_id(&name)) {
+                            if sheet != self.sheet {
+                                self.switch_sheet(workbook, sheet);
+                            }
                         }
+                        self.move_cursor(workbook, (origin.adr.1, origin.adr.0));
                     }
+                } else if key == KEY_UP {
+                    self.move_cursor(workbook, (self.grid_cursor.0.saturating_sub(1).max(0), self.grid_cursor.1));
+                } else if key == KEY_DOWN {
+                    self.move_cursor(workbook, (self.grid_cursor.0.saturating_add(1), self.grid_cursor.1));
                 } else if key == KEY_LEFT {
-                    if self.grid_cursor.1 == 0 {
-                        self.grid_cursor.1 = self.grid_dimensions.1 - 1;
-                    } else {
-                        self.grid_cursor.1 = (self.grid_cursor.1 - 1) % self.grid_dimensions.1;
-                    }
-                    mv(self.grid_cursor.0 * (CELL_HEIGHT + 1) + CELL_VERT_OFFSET + 1, self.grid_cursor.1 * (CELL_WIDTH + 1) + CELL_HORIZ_OFFSET + 1);
-                    self.text = match grid.get_cell_text(cursor_pos_to_cell_address(self.grid_cursor)) {
-                        Some(val) => val.to_owned(),
-                        None => "".to_string(),
-                    };
-                    wmove(self.editor_window, 1, 0);
-                    wclrtoeol(self.editor_window);
-                    waddstr(self.editor_window, &self.text);
-                    wrefresh(self.editor_window);
-                    self.set_result("");
-
-                    if let Some(result) = grid.get_cell_value(cursor_pos_to_cell_address(self.grid_cursor)) {
-                        match result {
-                            Ok(val) => self.set_result(&val.to_string()),
-                            Err(err) => self.set_result(&err),
-                        }
-                    }
- 
+                    self.move_cursor(workbook, (self.grid_cursor.0, self.grid_cursor.1.saturating_sub(1).max(0)));
                 } else if key == KEY_RIGHT {
-                    self.grid_cursor.1 = (self.grid_cursor.1 + 1) % self.grid_dimensions.1;
-                    mv(self.grid_cursor.0 * (CELL_HEIGHT + 1) + CELL_VERT_OFFSET + 1, self.grid_cursor.1 * (CELL_WIDTH + 1) + CELL_HORIZ_OFFSET + 1);
-                    self.text = match grid.get_cell_text(cursor_pos_to_cell_address(self.grid_cursor)) {
-                        Some(val) => val.to_owned(),
-                        None => "".to_string(),
-                    };
-                    wmove(self.editor_window, 1, 0);
-                    wclrtoeol(self.editor_window);
-                    waddstr(self.editor_window, &self.text);
-                    wrefresh(self.editor_window);
-                    self.set_result("");
-
-                    if let Some(result) = grid.get_cell_value(cursor_pos_to_cell_address(self.grid_cursor)) {
-                        match result {
-                            Ok(val) => self.set_result(&val.to_string()),
-                            Err(err) => self.set_result(&err),
-                        }
-                    }
-                    
+                    self.move_cursor(workbook, (self.grid_cursor.0, self.grid_cursor.1.saturating_add(1)));
                 } else {
-                    mv(self.grid_cursor.0 * (CELL_HEIGHT + 1) + CELL_VERT_OFFSET + 1, self.grid_cursor.1 * (CELL_WIDTH + 1) + CELL_HORIZ_OFFSET + 1);
+                    mv(cursor_y, cursor_x);
                 }
             }
-            Mode::Editor => {
+            Mode::Editor | Mode::Command => {
                 let mut curs_y = 0;
                 let mut curs_x = 0;
                 getyx(self.editor_window, &mut curs_y, &mut curs_x);
@@ -210,19 +243,16 @@ impl Interface {
                 wrefresh(self.editor_window);
 
                 let key = getch();
-                if key == KEY_ENTER || key == '\n' as i32 {
-                    grid.set_cell_text(cursor_pos_to_cell_address(self.grid_cursor), self.text.to_owned());
-
-                    self.set_result("");
-
-                    if let Some(result) = grid.get_cell_value(cursor_pos_to_cell_address(self.grid_cursor)) {
-                        match result {
-                            Ok(val) => self.set_result(&val.to_string()),
-                            Err(err) => self.set_result(&err),
-                        }
-                    }
-
-                    wmove(self.grid_window, self.grid_cursor.0 * (CELL_HEIGHT + 1) + CELL_VERT_OFFSET + 1, self.grid_cursor.1 * (CELL_WIDTH + 1) + CELL_HORIZ_OFFSET + 1);
+                if (key == KEY_ENTER || key == '\n' as i32) && matches!(self.mode, Mode::Command) {
+                    let command = self.text.to_owned();
+                    self.run_command(workbook, &command);
+                } else if key == KEY_ENTER || key == '\n' as i32 {
+                    let updated = workbook.set_cell_text(self.sheet, cursor_pos_to_cell_address(self.grid_cursor), self.text.to_owned());
+                    self.update_cells(workbook, &updated);
+                    self.show_cell_result(workbook);
+
+                    let (cursor_y, cursor_x) = self.cell_position(self.grid_cursor);
+                    wmove(self.grid_window, cursor_y, cursor_x);
                     self.mode = Mode::Grid;
                 } else if key == KEY_BACKSPACE {
                     wmove(self.editor_window, 1, cmp::max(curs_x - 1, 0));
@@ -247,6 +277,298 @@ impl Interface {
         result
     }
 
+    /// Switches to the command mode with the given text already typed in the editor
+    fn start_command(&mut self, text: &str) {
+        self.text = text.to_string();
+        wmove(self.editor_window, 1, 0);
+        wclrtoeol(self.editor_window);
+        waddstr(self.editor_window, &self.text);
+        wrefresh(self.editor_window);
+        self.set_result("Command: name = formula, name =, name, nothing to list names, insert/delete row/column [count], add sheet name, rename sheet name, delete sheet, copy, cut, paste, paste values");
+        wmove(self.editor_window, 1, self.text.len() as i32);
+        self.mode = Mode::Command;
+    }
+
+    /// Runs a command typed after ':', such as "name = formula", "insert row 2" or "add sheet name"
+    fn run_command(&mut self, workbook: &mut Workbook, command: &str) {
+        self.mode = Mode::Grid;
+
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let count = match words.get(2) {
+            Some(word) => word.parse::<i32>().ok().filter(|count| *count > 0),
+            None => Some(1),
+        };
+        let message = match (words.as_slice(), count) {
+            ([action @ ("insert" | "delete"), axis @ ("row" | "rows" | "column" | "columns"), ..], Some(count)) if words.len() <= 3 => {
+                let CellAddress(col, row) = cursor_pos_to_cell_address(self.grid_cursor);
+                match (*action, axis.starts_with("row")) {
+                    ("insert", true) => workbook.insert_rows(self.sheet, row, count),
+                    ("insert", false) => workbook.insert_columns(self.sheet, col, count),
+                    (_, true) => workbook.delete_rows(self.sheet, row, count),
+                    (_, false) => workbook.delete_columns(self.sheet, col, count),
+                };
+                self.update_grid(workbook);
+                format!("{} {} {}(s)", if *action == "insert" { "Inserted" } else { "Deleted" }, count, axis.trim_end_matches('s'))
+            }
+            ([action @ ("insert" | "delete"), "row" | "rows" | "column" | "columns", ..], _) => format!("Usage: {action} row|column [count]"),
+            ([action @ ("add" | "rename" | "delete"), "sheet", ..], _) => self.run_sheet_command(workbook, action, &words[2..]),
+            ([action @ ("copy" | "cut")], _) => {
+                let message = self.copy(workbook, *action == "cut");
+                self.update_grid(workbook);
+                message
+            }
+            (["paste"], _) => self.paste(workbook, false),
+            (["paste", "values"], _) => self.paste(workbook, true),
+            ([], _) => {
+                let names: Vec<String> = workbook.get_names().iter().map(|(name, formula)| format!("{name} = {formula}")).collect();
+                if names.is_empty() {
+                    String::from("No names defined")
+                } else {
+                    names.join(", ")
+                }
+            }
+            _ => self.run_name_command(workbook, command),
+        };
+
+        // Brings back the text of the cell under the cursor in place of the command
+        self.move_cursor(workbook, self.grid_cursor);
+        self.set_result(&message);
+    }
+
+    /// Adds, renames or deletes a sheet, returning the message to show
+    fn run_sheet_command(&mut self, workbook: &mut Workbook, action: &str, arguments: &[&str]) -> String {
+        match (action, arguments) {
+            ("add", [name]) => match workbook.add_sheet(name) {
+                Ok(sheet) => {
+                    self.switch_sheet(workbook, sheet);
+                    format!("Added sheet {name}")
+                }
+                Err(err) => err.to_string(),
+            },
+            ("rename", [name]) => match workbook.rename_sheet(self.sheet, name) {
+                Ok(updated) => {
+                    self.update_cells(workbook, &updated);
+                    self.draw_tabs(workbook);
+                    format!("Renamed sheet to {name}")
+                }
+                Err(err) => err.to_string(),
+            },
+            ("delete", []) => {
+                let sheets = workbook.get_sheets();
+                let index = sheets.iter().position(|(sheet, _)| *sheet == self.sheet).unwrap_or(0);
+                let name = sheets[index].1.to_owned();
+                let next = sheets.get(index + 1).or(sheets.get(index.wrapping_sub(1))).map(|(sheet, _)| *sheet);
+                match workbook.remove_sheet(self.sheet) {
+                    Ok(_) => {
+                        self.positions.remove(&self.sheet);
+                        if let Some(sheet) = next {
+                            self.switch_sheet(workbook, sheet);
+                        }
+                        format!("Deleted sheet {name}")
+                    }
+                    Err(err) => err.to_string(),
+                }
+            }
+            ("delete", _) => String::from("Usage: delete sheet"),
+            _ => format!("Usage: {action} sheet name"),
+        }
+    }
+
+    /// Cells of the selection, or the cell under the cursor when nothing is selected
+    fn selected_range(&self) -> CellRange {
+        let start = cursor_pos_to_cell_address(self.selection.unwrap_or(self.grid_cursor));
+        let end = cursor_pos_to_cell_address(self.grid_cursor);
+        CellRange(
+            CellAddress(cmp::min(start.0, end.0), cmp::min(start.1, end.1)),
+            CellAddress(cmp::max(start.0, end.0), cmp::max(start.1, end.1)),
+        )
+    }
+
+    /// Puts the selected cells on the clipboard and ends the selection
+    fn copy(&mut self, workbook: &Workbook, cut: bool) -> String {
+        let range = self.selected_range();
+        let mut cells: HashMap<CellAddress, (String, Option<Primitive>)> = HashMap::new();
+        for y in range.0.1..=range.1.1 {
+            for x in range.0.0..=range.1.0 {
+                let adr = CellAddress(x, y);
+                let text = workbook.get_cell_text(self.sheet, adr).cloned().unwrap_or_default();
+                let val = match workbook.get_cell_value(self.sheet, adr) {
+                    Some(Ok(val)) => Some(val.clone()),
+                    _ => None,
+                };
+                if !text.is_empty() || val.is_some() {
+                    cells.insert(adr, (text, val));
+                }
+            }
+        }
+
+        self.clipboard = Some(Clipboard { sheet: self.sheet, range, cells, cut });
+        self.selection = None;
+        let range_text = if range.0 == range.1 { range.0.to_string() } else { range.to_string() };
+        format!("{} {range_text}", if cut { "Cut" } else { "Copied" })
+    }
+
+    /// Pastes the clipboard at the cursor, moving cut cells and retargeting copied formulas
+    fn paste(&mut self, workbook: &mut Workbook, values_only: bool) -> String {
+        let Some(clipboard) = self.clipboard.take() else {
+            return String::from("Nothing to paste");
+        };
+
+        let to = cursor_pos_to_cell_address(self.grid_cursor);
+        let (from, size) = (clipboard.range.0, (clipboard.range.1.0 - clipboard.range.0.0, clipboard.range.1.1 - clipboard.range.0.1));
+        let Some(end) = to.0.checked_add(size.0).zip(to.1.checked_add(size.1)) else {
+            self.clipboard = Some(clipboard);
+            return String::from("Cannot paste past the edge of the grid");
+        };
+
+        if clipboard.cut && !values_only {
+            if let Err(err) = workbook.move_cells(clipboard.sheet, clipboard.range, self.sheet, to) {
+                self.clipboard = Some(clipboard);
+                return err.to_string();
+            }
+        } else {
+            if clipboard.cut {
+                for (adr, (text, _)) in &clipboard.cells {
+                    if !text.is_empty() {
+                        workbook.set_cell_text(clipboard.sheet, *adr, String::new());
+                    }
+                }
+            }
+
+            for y in 0..=size.1 {
+                for x in 0..=size.0 {
+                    let source = CellAddress(from.0 + x, from.1 + y);
+                    let target = CellAddress(to.0 + x, to.1 + y);
+                    let text = match clipboard.cells.get(&source) {
+                        Some((_, val)) if values_only => val.as_ref().and_then(literal_text).unwrap_or_default(),
+                        Some((text, _)) => match text.strip_prefix('=') {
+                            Some(formula) => format!("={}", workbook.retarget_formula(formula, source, target)),
+                            None => text.clone(),
+                        },
+                        None => String::new(),
+                    };
+
+                    // Leaves empty cells without text rather than giving them empty text
+                    if !text.is_empty() || workbook.get_cell_text(self.sheet, target).is_some_and(|text| !text.is_empty()) {
+                        workbook.set_cell_text(self.sheet, target, text);
+                    }
+                }
+            }
+        }
+
+        self.update_grid(workbook);
+        let pasted = CellRange(to, CellAddress(end.0, end.1));
+        let range_text = if pasted.0 == pasted.1 { pasted.0.to_string() } else { pasted.to_string() };
+        if !clipboard.cut {
+            self.clipboard = Some(clipboard);
+        }
+        format!("Pasted {}to {range_text}", if values_only { "values " } else { "" })
+    }
+
+    /// Shows the given sheet with the cursor it had when it was last shown
+    fn switch_sheet(&mut self, workbook: &Workbook, sheet: SheetId) {
+        self.positions.insert(self.sheet, (self.grid_cursor, self.scroll_offset));
+        self.selection = None;
+        let (cursor, offset) = self.positions.get(&sheet).copied().unwrap_or(((0, 0), (0, 0)));
+        self.sheet = sheet;
+        self.grid_cursor = cursor;
+        self.scroll_offset = offset;
+
+        self.update_grid(workbook);
+        self.draw_tabs(workbook);
+        self.move_cursor(workbook, cursor);
+    }
+
+    /// Draws the name of every sheet in the tab window, highlighting the shown one
+    fn draw_tabs(&self, workbook: &Workbook) {
+        wmove(self.tab_window, 0, 0);
+        wclrtoeol(self.tab_window);
+        for (sheet, name) in workbook.get_sheets() {
+            if sheet == self.sheet {
+                wattron(self.tab_window, A_REVERSE());
+            }
+            waddstr(self.tab_window, &format!(" {name} "));
+            wattroff(self.tab_window, A_REVERSE());
+            waddstr(self.tab_window, " ");
+        }
+        wrefresh(self.tab_window);
+    }
+
+    /// Defines, removes or shows a name, returning the message to show
+    fn run_name_command(&mut self, workbook: &mut Workbook, command: &str) -> String {
+        match command.split_once('=') {
+            Some((name, formula)) if formula.trim().is_empty() => {
+                let updated = workbook.remove_name(name.trim());
+                self.update_cells(workbook, &updated);
+                format!("Removed {}", name.trim())
+            }
+            Some((name, formula)) => match workbook.set_name(self.sheet, name.trim(), formula.trim()) {
+                Ok(updated) => {
+                    self.update_cells(workbook, &updated);
+                    format!("Defined {}", name.trim())
+                }
+                Err(err) => err.to_string(),
+            },
+            None => match workbook.get_name(command.trim()) {
+                Some(formula) => format!("{} = {}", command.trim(), formula),
+                None => format!("No name {}", command.trim()),
+            },
+        }
+    }
+
+    /// Moves the cursor to the given (row, column), scrolling to keep it visible
+    fn move_cursor(&mut self, workbook: &Workbook, cursor: (i32, i32)) {
+        let previous_cursor = self.grid_cursor;
+        let previous_offset = self.scroll_offset;
+        self.grid_cursor = cursor;
+
+        let (num_rows, num_cols) = self.visible_cells();
+        if self.grid_cursor.0 < self.scroll_offset.0 {
+            self.scroll_offset.0 = self.grid_cursor.0;
+        } else if self.grid_cursor.0 - self.scroll_offset.0 >= num_rows {
+            self.scroll_offset.0 = self.grid_cursor.0 - num_rows + 1;
+        }
+        if self.grid_cursor.1 < self.scroll_offset.1 {
+            self.scroll_offset.1 = self.grid_cursor.1;
+        } else if self.grid_cursor.1 - self.scroll_offset.1 >= num_cols {
+            self.scroll_offset.1 = self.grid_cursor.1 - num_cols + 1;
+        }
+
+        if self.scroll_offset != previous_offset || self.selection.is_some() {
+            self.update_grid(workbook);
+        } else {
+            self.update_cells(workbook, &[(self.sheet, cursor_pos_to_cell_address(previous_cursor)), (self.sheet, cursor_pos_to_cell_address(self.grid_cursor))]);
+        }
+
+        let (cursor_y, cursor_x) = self.cell_position(self.grid_cursor);
+        mv(cursor_y, cursor_x);
+        self.text = match workbook.get_cell_text(self.sheet, cursor_pos_to_cell_address(self.grid_cursor)) {
+            Some(val) => val.to_owned(),
+            None => "".to_string(),
+        };
+
+        wmove(self.editor_window, 1, 0);
+        wclrtoeol(self.editor_window);
+        waddstr(self.editor_window, &self.text);
+        wrefresh(self.editor_window);
+        self.show_cell_result(workbook);
+    }
+
+    /// Shows the value of the cell under the cursor in the result window
+    fn show_cell_result(&self, workbook: &Workbook) {
+        self.set_result("");
+
+        if let Some(result) = workbook.get_cell_value(self.sheet, cursor_pos_to_cell_address(self.grid_cursor)) {
+            match result {
+                Ok(val) => self.set_result(&val.to_string()),
+                Err(err) => match err.origin() {
+                    Some(_) => self.set_result(&format!("{err} - press g to go to the origin")),
+                    None => self.set_result(&err.to_string()),
+                },
+            }
+        }
+    }
+
     fn set_result(&self, text: &str) {
         wmove(self.result_window, 1, 0);
         wclrtoeol(self.result_window);
@@ -254,27 +576,73 @@ impl Interface {
         wrefresh(self.result_window);
     }
 
-    fn update_grid(&self, cell_values: Vec<(&CellAddress, &Result<Primitive, String>)>) {
-        for cell in cell_values {
-            if (cell.0.1, cell.0.0) == self.grid_cursor {
-                wattron(self.grid_window, A_BOLD());
-            }
-            wmove(self.grid_window, cell.0.1 * (CELL_HEIGHT + 1) + CELL_VERT_OFFSET + 1, cell.0.0 * (CELL_WIDTH + 1) + CELL_HORIZ_OFFSET + 1);
-            let _ = match cell.1 {
-                Ok(val) => waddstr(self.grid_window, &format!("{0:.1$}", val.to_string(), CELL_WIDTH as usize)),
-                Err(_) => waddstr(self.grid_window, "ERROR"),
-            };
-            if (cell.0.1, cell.0.0) == self.grid_cursor {
-                wattroff(self.grid_window, A_BOLD());
+    /// Redraws every cell of the grid
+    fn update_grid(&self, workbook: &Workbook) {
+        wclear(self.grid_window);
+        for (adr, _) in workbook.get_all_cell_values(self.sheet) {
+            self.draw_cell(workbook, adr);
+        }
+        if self.selection.is_some() {
+            // Only the visible part of the selection is drawn, as it may be large
+            let range = self.selected_range();
+            let (num_rows, num_cols) = self.visible_cells();
+            for y in cmp::max(range.0.1, self.scroll_offset.0)..=cmp::min(range.1.1, self.scroll_offset.0 + num_rows) {
+                for x in cmp::max(range.0.0, self.scroll_offset.1)..=cmp::min(range.1.0, self.scroll_offset.1 + num_cols) {
+                    self.draw_cell(workbook, &CellAddress(x, y));
+                }
             }
         }
 
         self.draw_grid();
         if let Mode::Grid = self.mode {
-            wmove(self.grid_window, self.grid_cursor.0 * (CELL_HEIGHT + 1) + CELL_VERT_OFFSET + 1, self.grid_cursor.1 * (CELL_WIDTH + 1) + CELL_HORIZ_OFFSET + 1);
+            let (cursor_y, cursor_x) = self.cell_position(self.grid_cursor);
+            wmove(self.grid_window, cursor_y, cursor_x);
+        }
+        wrefresh(self.grid_window);
+    }
+
+    /// Redraws the given cells that are on the shown sheet
+    fn update_cells(&self, workbook: &Workbook, cells: &[SheetAddress]) {
+        for (sheet, adr) in cells {
+            if *sheet == self.sheet {
+                self.draw_cell(workbook, adr);
+            }
+        }
+
+        if let Mode::Grid = self.mode {
+            let (cursor_y, cursor_x) = self.cell_position(self.grid_cursor);
+            wmove(self.grid_window, cursor_y, cursor_x);
         }
         wrefresh(self.grid_window);
     }
+
+    fn draw_cell(&self, workbook: &Workbook, adr: &CellAddress) {
+        if !self.is_visible((adr.1, adr.0)) {
+            return;
+        }
+
+        if (adr.1, adr.0) == self.grid_cursor {
+            wattron(self.grid_window, A_BOLD());
+        }
+        let selected = self.selection.is_some() && self.selected_range().contains(adr);
+        if selected {
+            wattron(self.grid_window, A_REVERSE());
+        }
+        let (cell_y, cell_x) = self.cell_position((adr.1, adr.0));
+        wmove(self.grid_window, cell_y, cell_x);
+        let text = match workbook.get_cell_value(self.sheet, *adr) {
+            Some(Ok(val)) => val.to_string(),
+            Some(Err(err)) => err.short_code().to_string(),
+            None => String::new(),
+        };
+        waddstr(self.grid_window, &format!("{0:1$.1$}", text, CELL_WIDTH as usize));
+        if (adr.1, adr.0) == self.grid_cursor {
+            wattroff(self.grid_window, A_BOLD());
+        }
+        if selected {
+            wattroff(self.grid_window, A_REVERSE());
+        }
+    }
 }
 
 /// Taken from Stack Overflow
@@ -298,7 +666,7 @@ fn cursor_pos_to_cell_address(cursor_pos: (i32, i32)) -> CellAddress {
 
 
 enum Mode {
-    Grid, Editor
+    Grid, Editor, Command
 }
 
 pub enum InterfaceRequest {
@@ -306,7 +674,7 @@ pub enum InterfaceRequest {
 }
 
 pub enum Response {
-    None, UpdatedCells(Vec<(CellAddress, Result<Primitive, String>)>), CellData(String, Primitive),
+    None, UpdatedCells(Vec<(CellAddress, Result<Primitive, CellError>)>), CellData(String, Primitive),
 }
 
  
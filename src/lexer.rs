@@ -1,5 +1,8 @@
+use crate::{error::CellError, model::CellAddress};
+
 #[derive(Debug,Eq, PartialEq, Clone, Copy)]
 pub enum TokenType {
+    A1Reference,
     BitwiseAnd,
     BitwiseNot,
     BitwiseOr,
@@ -7,15 +10,18 @@ pub enum TokenType {
     CloseBracket,
     CloseCurlyBracket,
     CloseParenthesis,
+    Colon,
     Comma,
     Divide,
     DoubleEquals,
     Power,
+    DeletedReference,
     False,
     FloatCast,
     FloatLiteral,
     GreaterThan,
     GreaterThanOrEqual,
+    Identifier,
     IntegerCast,
     IntegerLiteral,
     LeftShift,
@@ -24,9 +30,6 @@ pub enum TokenType {
     LogicalAnd,
     LogicalNot,
     LogicalOr,
-    Max,
-    Mean,
-    Min,
     Minus,
     Modulus,
     NotEquals,
@@ -35,8 +38,8 @@ pub enum TokenType {
     OpenParenthesis,
     Plus,
     RightShift,
+    Semicolon,
     StringLiteral,
-    Sum,
     Multiply,
     True,
 }
@@ -49,7 +52,7 @@ pub struct Token<'a> {
     pub end: usize,
 }
 
-pub fn lex<'a>(text: &'a str) -> Result<Vec<Token>, String> {
+pub fn lex<'a>(text: &'a str) -> Result<Vec<Token>, CellError> {
     Lexer::new(text).lex()
 }
 
@@ -66,7 +69,7 @@ impl<'a> Lexer<'a> {
         Lexer { text: text, cur_index: 0, start_index: 0, tokens: Vec::new() }
     }
 
-    fn lex(mut self) -> Result<Vec<Token<'a>>, String> {
+    fn lex(mut self) -> Result<Vec<Token<'a>>, CellError> {
         while !self.is_at_end() {
             if self.has("|") {
                 self.capture();
@@ -112,6 +115,9 @@ impl<'a> Lexer<'a> {
             } else if self.has(",") {
                 self.capture();
                 self.emit_token(TokenType::Comma);
+            } else if self.has(";") {
+                self.capture();
+                self.emit_token(TokenType::Semicolon);
             } else if self.has(")") {
                 self.capture();
                 self.emit_token(TokenType::CloseParenthesis);
@@ -130,39 +136,31 @@ impl<'a> Lexer<'a> {
             } else if self.has("[") {
                 self.capture();
                 self.emit_token(TokenType::OpenBracket);
-            }else if self.has("float") {
-                self.capture();
-                self.capture();
-                self.capture();
-                self.capture();
-                self.capture();
-                self.emit_token(TokenType::FloatCast);
-            } else if self.has("int") {
-                self.capture();
-                self.capture();
-                self.capture();
-                self.emit_token(TokenType::IntegerCast);
-            } else if self.has("max") {
-                self.capture();
+            } else if self.has(":") {
                 self.capture();
+                self.emit_token(TokenType::Colon);
+            } else if self.has_identifier_start() || self.has("$") { // Keywords, identifiers and A1 references
                 self.capture();
-                self.emit_token(TokenType::Max);
-            } else if self.has("mean") {
-                self.capture();
-                self.capture();
-                self.capture();
-                self.capture();
-                self.emit_token(TokenType::Mean);
-            } else if self.has("min") {
-                self.capture();
-                self.capture();
-                self.capture();
-                self.emit_token(TokenType::Min);
-            } else if self.has("sum") {
-                self.capture();
-                self.capture();
-                self.capture();
-                self.emit_token(TokenType::Sum);
+                while self.has_identifier_character() || self.has("$") {
+                    self.capture();
+                }
+                match &self.text[self.start_index..self.cur_index] {
+                    "float" => self.emit_token(TokenType::FloatCast),
+                    "int" => self.emit_token(TokenType::IntegerCast),
+                    "true" => self.emit_token(TokenType::True),
+                    "false" => self.emit_token(TokenType::False),
+                    // Name of a called function, such as LOG10, even if it reads as a cell
+                    text if !text.contains('$') && self.has("(") => self.emit_token(TokenType::Identifier),
+                    text if CellAddress::from_a1(text).is_some() => self.emit_token(TokenType::A1Reference),
+                    text if text.contains('$') => {
+                        return Err(CellError::Parse {
+                            message: format!("Invalid cell reference {text}"),
+                            start: self.start_index,
+                            end: self.cur_index - 1,
+                        });
+                    }
+                    _ => self.emit_token(TokenType::Identifier),
+                }
             } else if self.has("-") {
                 self.capture();
                 if self.has_digit() {
@@ -211,6 +209,11 @@ impl<'a> Lexer<'a> {
                 } else {
                     self.emit_token(TokenType::LogicalNot);
                 }
+            } else if self.has("#REF!") {
+                for _ in 0.."#REF!".len() {
+                    self.capture();
+                }
+                self.emit_token(TokenType::DeletedReference);
             } else if self.has("~") {
                 self.capture();
                 self.emit_token(TokenType::BitwiseNot);
@@ -224,7 +227,11 @@ impl<'a> Lexer<'a> {
                         self.capture();
                         self.capture();
                     } else if self.is_at_end() {
-                        return Err(format!("Unclosed string literal starting at index {}: \"{}", self.start_index - 1, &self.text[self.start_index..self.cur_index]));
+                        return Err(CellError::Parse {
+                            message: format!("Unclosed string literal \"{}", &self.text[self.start_index..self.cur_index]),
+                            start: self.start_index - 1,
+                            end: self.cur_index - 1,
+                        });
                     }else {
                         self.capture();
                     }
@@ -233,19 +240,6 @@ impl<'a> Lexer<'a> {
                     self.emit_token(TokenType::StringLiteral);
                     self.abandon(); // Skip next "
                 }
-            } else if self.has("true") { // Booleans
-                self.capture();
-                self.capture();
-                self.capture();
-                self.capture();
-                self.emit_token(TokenType::True);
-            } else if self.has("false") {
-                self.capture();
-                self.capture();
-                self.capture();
-                self.capture();
-                self.capture();
-                self.emit_token(TokenType::False);
             } else if self.has_digit() {
                 self.capture();
                 while self.has_digit() {
@@ -263,12 +257,20 @@ impl<'a> Lexer<'a> {
             } else if self.has_whitespace() {
                 self.abandon();
             } else {
-                return Err(format!("Unexpected character '{}' at index {}", &self.text[self.start_index..self.start_index + 1], self.start_index));
+                return Err(CellError::Parse {
+                    message: format!("Unexpected character '{}'", self.current().unwrap_or_default()),
+                    start: self.start_index,
+                    end: self.start_index,
+                });
             }
         }
 
         if self.cur_index != self.start_index {
-            Err(format!("An unexpected string \"{}\" at index {}", &self.text[self.start_index..self.text.len()], self.start_index))
+            Err(CellError::Parse {
+                message: format!("An unexpected string \"{}\"", &self.text[self.start_index..self.text.len()]),
+                start: self.start_index,
+                end: self.text.len() - 1,
+            })
         } else {
             Ok(self.tokens)
         }
@@ -279,23 +281,36 @@ impl<'a> Lexer<'a> {
     }
 
     fn has(&self, str: &str) -> bool {
-        self.cur_index + str.len() - 1 < self.text.len() && &self.text[self.cur_index..self.cur_index + str.len()] == str 
-    } 
+        self.text.get(self.cur_index..).is_some_and(|rest| rest.starts_with(str))
+    }
+
+    /// Character at the current position, which moves a whole character at a time
+    fn current(&self) -> Option<char> {
+        self.text.get(self.cur_index..)?.chars().next()
+    }
 
     fn has_whitespace(&self) -> bool {
-        !self.is_at_end() && self.text.chars().nth(self.cur_index).unwrap().is_whitespace()
+        self.current().is_some_and(|character| character.is_whitespace())
+    }
+
+    fn has_identifier_start(&self) -> bool {
+        self.current().is_some_and(|character| character.is_ascii_alphabetic() || character == '_')
+    }
+
+    fn has_identifier_character(&self) -> bool {
+        self.current().is_some_and(|character| character.is_ascii_alphanumeric() || character == '_')
     }
 
     fn has_digit(&self) -> bool {
-        !self.is_at_end() && self.text.chars().nth(self.cur_index).unwrap().is_digit(10)
+        self.current().is_some_and(|character| character.is_ascii_digit())
     }
 
     fn capture(&mut self) {
-        self.cur_index += 1;
+        self.cur_index += self.current().map_or(1, char::len_utf8);
     }
 
     fn abandon(&mut self) {
-        self.cur_index += 1;
+        self.capture();
         self.start_index = self.cur_index;
     }
 
@@ -1,6 +1,9 @@
 pub mod model;
+pub mod error;
 pub mod environment;
+pub mod functions;
 pub mod grid;
+pub mod workbook;
 pub mod lexer;
 pub mod parser;
 pub mod interface;
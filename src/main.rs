@@ -1,12 +1,12 @@
-use spreadterm::{interface::Interface, grid::TextGrid};
+use spreadterm::{interface::Interface, workbook::Workbook};
 use ncurses::*;
 
 fn main() {
-    let mut interface = Interface::new((10, 10));
-    let mut grid = TextGrid::new((10, 10));
-    interface.setup();
+    let mut interface = Interface::new();
+    let mut workbook = Workbook::new();
+    interface.setup(&workbook);
     loop {
-        if !interface.update(&mut grid) {
+        if !interface.update(&mut workbook) {
             break;
         }
     }
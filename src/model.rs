@@ -1,9 +1,33 @@
-use std::fmt::Debug;
+use std::{collections::HashMap, fmt::Debug, rc::Rc};
 
-use crate::environment::Environment;
+use crate::{environment::Environment, error::CellError, functions::ArgumentValue};
 
 pub trait Evaluatable: ToString + Debug {
-    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, String>;
+    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, CellError>;
+
+    /// Cells whose values this expression reads when it is evaluated
+    fn references(&self) -> Vec<Reference>;
+
+    /// Names this expression looks up when it is evaluated, which may be defined in the workbook
+    fn names(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Moves the relative references by the given columns and rows, as when the formula is copied
+    /// to another cell, noting the new text of each moved reference. False if one leaves the grid
+    fn retarget(&mut self, _offset: (i32, i32), _edits: &mut Vec<(Span, String)>) -> bool {
+        true
+    }
+}
+
+/// Position of the text an expression was parsed from, up to but excluding the end
+pub type Span = (usize, usize);
+
+/// Retargets an operand, replacing it by #REF! if it moves off the grid
+fn retarget_operand(operand: &mut Box<dyn Evaluatable>, offset: (i32, i32), edits: &mut Vec<(Span, String)>) {
+    if !operand.retarget(offset, edits) {
+        *operand = Box::new(DeletedReference);
+    }
 }
 
 enum OperationType {
@@ -32,10 +56,16 @@ impl ToString for OperationType {
 
 #[derive(Clone, Debug)]
 pub enum Primitive {
-    Integer(i32),
-    Float(f32),
+    Integer(i64),
+    Float(f64),
     Boolean(bool),
     String(String),
+    /// Value of a cell with nothing in it
+    Empty,
+    /// Block of values, row by row, such as the values of a range of cells
+    Array(Vec<Vec<Primitive>>),
+    /// Function defined in a formula
+    Lambda(Rc<Closure>),
 }
 
 impl ToString for Primitive {
@@ -45,28 +75,66 @@ impl ToString for Primitive {
             Primitive::Float(val) => format!("{val}"),
             Primitive::Boolean(val) => format!("{val}"),
             Primitive::String(val) => format!("\"{val}\""),
+            Primitive::Empty => String::new(),
+            Primitive::Array(rows) => {
+                let rows: Vec<String> = rows.iter()
+                    .map(|row| row.iter().map(|val| val.to_string()).collect::<Vec<String>>().join(", "))
+                    .collect();
+                format!("{{{}}}", rows.join("; "))
+            }
+            Primitive::Lambda(closure) => format!("lambda({})", closure.to_string()),
         }
     }
 }
 
 impl Evaluatable for Primitive {
-    fn evaluate(&self, _environment: &Environment) -> Result<Primitive, String> {
+    fn evaluate(&self, _environment: &Environment) -> Result<Primitive, CellError> {
         match self {
             Primitive::Integer(val) => Ok(Primitive::Integer(val.clone())),
             Primitive::Float(val) => Ok(Primitive::Float(val.clone())),
             Primitive::Boolean(val) => Ok(Primitive::Boolean(val.clone())),
             Primitive::String(val) => Ok(Primitive::String(val.clone())),
+            Primitive::Empty => Ok(Primitive::Empty),
+            Primitive::Array(rows) => Ok(Primitive::Array(rows.clone())),
+            Primitive::Lambda(closure) => Ok(Primitive::Lambda(closure.clone())),
         }
     }
+
+    fn references(&self) -> Vec<Reference> {
+        Vec::new()
+    }
 }
 
 impl Primitive {
-    fn type_string(&self) -> String {
+    pub fn type_string(&self) -> String {
         match self {
             Primitive::Integer(_) => String::from("Integer"),
             Primitive::Float(_) => String::from("Float"),
             Primitive::Boolean(_) => String::from("Boolean"),
             Primitive::String(_) => String::from("String"),
+            Primitive::Empty => String::from("Empty"),
+            Primitive::Array(_) => String::from("Array"),
+            Primitive::Lambda(_) => String::from("Lambda"),
+        }
+    }
+
+    /// Value an empty operand takes on in arithmetic, bitwise and relational operations
+    fn or_zero(&self) -> Primitive {
+        match self {
+            Self::Empty => Self::Integer(0),
+            val => val.clone(),
+        }
+    }
+
+    /// Value an empty operand takes on when compared for equality with the given value, i.e. the
+    /// zero value of the other operand's type
+    fn or_default_for(&self, other: &Primitive) -> Primitive {
+        match (self, other) {
+            (Self::Empty, Self::Integer(_)) => Self::Integer(0),
+            (Self::Empty, Self::Float(_)) => Self::Float(0.0),
+            (Self::Empty, Self::Boolean(_)) => Self::Boolean(false),
+            (Self::Empty, Self::String(_)) => Self::String(String::new()),
+            (val, _) => val.clone(),
         }
     }
 
@@ -106,31 +174,31 @@ impl Primitive {
         }
     }
 
-    fn get_int_value(&self) -> Result<i32, String> {
+    fn get_int_value(&self) -> Result<i64, CellError> {
         match self {
             Self::Integer(val) => Ok(val.clone()),
-            _ => Err(format!("Invalid type to get integer value: {}", self.type_string()))
+            _ => Err(CellError::Value(format!("Invalid type to get integer value: {}", self.type_string())))
         }
     }
 
-    fn get_float_value(&self) -> Result<f32, String> {
+    fn get_float_value(&self) -> Result<f64, CellError> {
         match self {
             Self::Float(val) => Ok(val.clone()),
-            _ => Err(format!("Invalid type to get float value: {}", self.type_string()))
+            _ => Err(CellError::Value(format!("Invalid type to get float value: {}", self.type_string())))
         }
     }
     
-    fn get_boolean_value(&self) -> Result<bool, String> {
+    fn get_boolean_value(&self) -> Result<bool, CellError> {
         match self {
             Self::Boolean(val) => Ok(val.clone()),
-            _ => Err(format!("Invalid type to get Boolean value: {}", self.type_string()))
+            _ => Err(CellError::Value(format!("Invalid type to get Boolean value: {}", self.type_string())))
         }
     }
 
-    fn get_string_value<'a>(&'a self) -> Result<&'a str, String> {
+    fn get_string_value<'a>(&'a self) -> Result<&'a str, CellError> {
         match self {
             Self::String(val) => Ok(val),
-            _ => Err(format!("Invalid type to get String value: {}", self.type_string()))
+            _ => Err(CellError::Value(format!("Invalid type to get String value: {}", self.type_string())))
         }
     }
 }
@@ -198,7 +266,11 @@ impl ToString for Operation {
 }
 
 impl Evaluatable for Operation {
-    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, String> {
+    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, CellError> {
+        if let Self::LogicalAnd(v1, v2) | Self::LogicalOr(v1, v2) = self {
+            return self.short_circuit(v1.as_ref(), v2.as_ref(), environment);
+        }
+
         let (result1, result2) = get_results(self, environment);
 
         let results = unpack_results(result1, result2);
@@ -210,21 +282,88 @@ impl Evaluatable for Operation {
             Err(e) => return Err(e)
         }
 
+        match (&val1, &val2_option) {
+            (Primitive::Array(_), _) | (_, Some(Primitive::Array(_))) => self.element_wise(&val1, &val2_option),
+            _ => self.apply(val1, val2_option),
+        }
+    }
+
+    fn references(&self) -> Vec<Reference> {
+        match self.get_operands() {
+            (val1, Some(val2)) => {
+                let mut references = val1.references();
+                references.append(&mut val2.references());
+                references
+            }
+            (val1, None) => val1.references(),
+        }
+    }
+
+    fn retarget(&mut self, offset: (i32, i32), edits: &mut Vec<(Span, String)>) -> bool {
+        for operand in self.get_operands_mut() {
+            retarget_operand(operand, offset, edits);
+        }
+        true
+    }
+
+    fn names(&self) -> Vec<String> {
+        match self.get_operands() {
+            (val1, Some(val2)) => {
+                let mut names = val1.names();
+                names.append(&mut val2.names());
+                names
+            }
+            (val1, None) => val1.names(),
+        }
+    }
+}
+
+
+fn type_mismatch_error<'a>(val1: &Primitive, val2_option: &Option<Primitive>, operation: &Operation) -> Result<Primitive, CellError> {
+    if let Some(val2) = val2_option {
+        Err(CellError::Value(format!("Incompatible types: {} and {} for {} operation",val1.type_string(), val2.type_string(), operation.get_type().to_string())))
+    } else {
+        Err(CellError::Value(format!("Incompatible type: {} for {} operation", val1.type_string(), operation.get_type().to_string())))
+    }   
+}
+
+impl Operation {
+    /// Evaluates && and ||, only evaluating the right operand when the left one does not already
+    /// decide the result
+    fn short_circuit(&self, v1: &dyn Evaluatable, v2: &dyn Evaluatable, environment: &Environment) -> Result<Primitive, CellError> {
+        let val1 = v1.evaluate(environment)?;
+
+        match (self, &val1) {
+            (Self::LogicalAnd(_, _), Primitive::Boolean(false)) => Ok(Primitive::Boolean(false)),
+            (Self::LogicalOr(_, _), Primitive::Boolean(true)) => Ok(Primitive::Boolean(true)),
+            _ => {
+                let val2_option = Some(v2.evaluate(environment)?);
+                match (&val1, &val2_option) {
+                    (Primitive::Array(_), _) | (_, Some(Primitive::Array(_))) => self.element_wise(&val1, &val2_option),
+                    _ => self.apply(val1, val2_option),
+                }
+            }
+        }
+    }
+
+    /// Applies the operation to single values
+    fn apply(&self, val1: Primitive, val2_option: Option<Primitive>) -> Result<Primitive, CellError> {
         match self.get_type() {
             OperationType::Arithmetic => {
-                arithmetic(self, &val1, &val2_option.unwrap())
+                arithmetic(self, &val1.or_zero(), &val2_option.unwrap().or_zero())
             }
             OperationType::Logical => {
                 logic(self, &val1, &val2_option)   
             }
             OperationType::Bitwise => {
-                bitwise(self, &val1, &val2_option)
+                bitwise(self, &val1.or_zero(), &val2_option.map(|val2| val2.or_zero()))
             }
             OperationType::Equality => {
-                equality(self, &val1, &val2_option.unwrap())
+                let val2 = val2_option.unwrap();
+                equality(self, &val1.or_default_for(&val2), &val2.or_default_for(&val1))
             }
             OperationType::Relational => {
-                relation(self, &val1, &val2_option.unwrap())
+                relation(self, &val1.or_zero(), &val2_option.unwrap().or_zero())
             }
             OperationType::FloatToInt => {
                 cast_to_integer(&val1)
@@ -234,18 +373,39 @@ impl Evaluatable for Operation {
             }
         }
     }
-}
 
+    /// Applies the operation to each element of the array operands, pairing the elements of two
+    /// arrays by position and pairing every element of an array with a single value
+    fn element_wise(&self, val1: &Primitive, val2_option: &Option<Primitive>) -> Result<Primitive, CellError> {
+        let (rows, cols) = match (val1, val2_option) {
+            (Primitive::Array(rows1), Some(Primitive::Array(rows2))) => {
+                if array_size(rows1) != array_size(rows2) {
+                    return Err(CellError::Value(format!("Arrays of different sizes for {} operation", self.get_type().to_string())));
+                }
+                array_size(rows1)
+            }
+            (Primitive::Array(rows), _) | (_, Some(Primitive::Array(rows))) => array_size(rows),
+            _ => panic!("Unexpected non-Array operands"),
+        };
 
-fn type_mismatch_error<'a>(val1: &Primitive, val2_option: &Option<Primitive>, operation: &Operation) -> Result<Primitive, String> {
-    if let Some(val2) = val2_option {
-        Err(format!("Incompatible types: {} and {} for {} operation",val1.type_string(), val2.type_string(), operation.get_type().to_string()))
-    } else {
-        Err(format!("Incompatible type: {} for {} operation", val1.type_string(), operation.get_type().to_string()))
-    }   
-}
+        let element = |val: &Primitive, row: usize, col: usize| match val {
+            Primitive::Array(vals) => vals[row][col].clone(),
+            val => val.clone(),
+        };
+
+        let mut result: Vec<Vec<Primitive>> = Vec::new();
+        for row in 0..rows {
+            let mut result_row: Vec<Primitive> = Vec::new();
+            for col in 0..cols {
+                let val2 = val2_option.as_ref().map(|val2| element(val2, row, col));
+                result_row.push(self.apply(element(val1, row, col), val2)?);
+            }
+            result.push(result_row);
+        }
+
+        Ok(Primitive::Array(result))
+    }
 
-impl Operation {
     fn get_type(&self) -> OperationType {
         match self {
             Self::Add(_, _) => OperationType::Arithmetic,
@@ -278,174 +438,697 @@ impl Operation {
             Self::IntToFloat(_) => OperationType::IntToFloat,
         }
     }
+
+    fn get_operands(&self) -> (&dyn Evaluatable, Option<&dyn Evaluatable>) {
+        match self {
+            Self::Add(v1, v2) | Self::Subtract(v1, v2) | Self::Multiply(v1, v2) | Self::Divide(v1, v2)
+            | Self::Modulus(v1, v2) | Self::Power(v1, v2) | Self::LogicalAnd(v1, v2) | Self::LogicalOr(v1, v2)
+            | Self::BitwiseAnd(v1, v2) | Self::BitwiseOr(v1, v2) | Self::BitwiseXor(v1, v2)
+            | Self::LeftShift(v1, v2) | Self::RightShift(v1, v2) | Self::Equals(v1, v2) | Self::NotEquals(v1, v2)
+            | Self::LessThan(v1, v2) | Self::LessThanOrEqual(v1, v2) | Self::GreaterThan(v1, v2)
+            | Self::GreaterThanOrEqual(v1, v2) => (v1.as_ref(), Some(v2.as_ref())),
+
+            Self::LogicalNot(v1) | Self::BitwiseNot(v1) | Self::FloatToInt(v1) | Self::IntToFloat(v1) => (v1.as_ref(), None),
+        }
+    }
+
+    fn get_operands_mut(&mut self) -> Vec<&mut Box<dyn Evaluatable>> {
+        match self {
+            Self::Add(v1, v2) | Self::Subtract(v1, v2) | Self::Multiply(v1, v2) | Self::Divide(v1, v2)
+            | Self::Modulus(v1, v2) | Self::Power(v1, v2) | Self::LogicalAnd(v1, v2) | Self::LogicalOr(v1, v2)
+            | Self::BitwiseAnd(v1, v2) | Self::BitwiseOr(v1, v2) | Self::BitwiseXor(v1, v2)
+            | Self::LeftShift(v1, v2) | Self::RightShift(v1, v2) | Self::Equals(v1, v2) | Self::NotEquals(v1, v2)
+            | Self::LessThan(v1, v2) | Self::LessThanOrEqual(v1, v2) | Self::GreaterThan(v1, v2)
+            | Self::GreaterThanOrEqual(v1, v2) => vec![v1, v2],
+
+            Self::LogicalNot(v1) | Self::BitwiseNot(v1) | Self::FloatToInt(v1) | Self::IntToFloat(v1) => vec![v1],
+        }
+    }
 }
 
+/// Identifier of a sheet in a workbook, which stays the same when the sheet is renamed or moved
+pub type SheetId = usize;
+
 /// Address of given cell
 #[derive(Eq, Hash, PartialEq, Clone, Copy, Debug)]
 pub struct CellAddress(pub i32, pub i32);
 
+/// Most letters naming a column in A1 form, such as B7
+pub const MAX_COLUMN_LETTERS: usize = 3;
+
+/// Whether the column and the row of a reference are absolute, written with a $ as in $A$1, so
+/// that they stay the same when the formula is moved to another cell. References are relative
+/// unless written otherwise
+#[derive(Eq, Hash, PartialEq, Clone, Copy, Debug, Default)]
+pub struct Absolute(pub bool, pub bool);
+
+impl CellAddress {
+    /// Address written in A1 form, such as B7 for the cell in column 1 and row 6, along with which
+    /// of its column and row are absolute. The column is named by up to three uppercase letters
+    /// and rows are numbered from 1, each optionally preceded by a $, as in $B$7
+    pub fn from_a1(text: &str) -> Option<(CellAddress, Absolute)> {
+        let (absolute_col, text) = match text.strip_prefix('$') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        let digits = text.find(|character: char| !character.is_ascii_uppercase())?;
+        let (letters, row) = text.split_at(digits);
+        let (absolute_row, row) = match row.strip_prefix('$') {
+            Some(rest) => (true, rest),
+            None => (false, row),
+        };
+        if letters.is_empty() || letters.len() > MAX_COLUMN_LETTERS || row.starts_with('0') || !row.chars().all(|character| character.is_ascii_digit()) {
+            return None;
+        }
+
+        match row.parse::<i32>() {
+            Ok(row) if row >= 1 => Some((CellAddress(column_number(letters), row - 1), Absolute(absolute_col, absolute_row))),
+            _ => None,
+        }
+    }
+
+    /// Address moved by the given columns and rows along its relative axes, None if it leaves the
+    /// grid
+    pub fn retarget(&self, absolute: Absolute, offset: (i32, i32)) -> Option<CellAddress> {
+        let col = if absolute.0 { self.0 } else { self.0.checked_add(offset.0)? };
+        let row = if absolute.1 { self.1 } else { self.1.checked_add(offset.1)? };
+        (col >= 0 && row >= 0).then_some(CellAddress(col, row))
+    }
+
+    /// Address in A1 form, or as [x, y] if it has no A1 form
+    pub fn to_a1(&self) -> String {
+        self.to_anchored_a1(Absolute::default())
+    }
+
+    /// Address in A1 form with a $ before its absolute column and row, or as [x, y] if it has no
+    /// A1 form, as for columns past ZZZ
+    pub fn to_anchored_a1(&self, absolute: Absolute) -> String {
+        if self.0 >= 0 && self.0 <= column_number(&"Z".repeat(MAX_COLUMN_LETTERS)) && self.1 >= 0 && self.1 < i32::MAX {
+            let dollar = |absolute: bool| if absolute { "$" } else { "" };
+            format!("{}{}{}{}", dollar(absolute.0), column_name(self.0), dollar(absolute.1), self.1 + 1)
+        } else {
+            format!("[{}, {}]", self.0, self.1)
+        }
+    }
+}
+
 impl ToString for CellAddress {
     fn to_string(&self) -> String {
-        format!("({}, {})", self.0, self.1)
+        self.to_a1()
+    }
+}
+
+/// Letters naming the given column in A1 form: A to Z for columns 0 to 25, then AA, AB and so on
+pub fn column_name(col: i32) -> String {
+    let mut letters = Vec::new();
+    let mut rest = col as i64 + 1;
+    while rest > 0 {
+        letters.push(b'A' + ((rest - 1) % 26) as u8);
+        rest = (rest - 1) / 26;
+    }
+    letters.iter().rev().map(|letter| *letter as char).collect()
+}
+
+/// Column named by the given uppercase letters in A1 form
+fn column_number(letters: &str) -> i32 {
+    letters.bytes().fold(0, |col, letter| col * 26 + (letter - b'A') as i32 + 1) - 1
+}
+
+/// Rectangular block of cells between two corners, inclusive, which evaluates to an array of the
+/// values of its cells
+#[derive(Eq, Hash, PartialEq, Clone, Copy, Debug)]
+pub struct CellRange(pub CellAddress, pub CellAddress);
+
+impl CellRange {
+    pub fn contains(&self, adr: &CellAddress) -> bool {
+        self.0.0 <= adr.0 && adr.0 <= self.1.0 && self.0.1 <= adr.1 && adr.1 <= self.1.1
+    }
+
+    /// Whether the two ranges have at least one cell in common
+    pub fn intersects(&self, other: &CellRange) -> bool {
+        self.0.0 <= other.1.0 && other.0.0 <= self.1.0 && self.0.1 <= other.1.1 && other.0.1 <= self.1.1
+    }
+
+    /// Number of cells in the range, none if its corners are the wrong way round
+    pub fn size(&self) -> i64 {
+        let width = (self.1.0 as i64 - self.0.0 as i64 + 1).max(0);
+        let height = (self.1.1 as i64 - self.0.1 as i64 + 1).max(0);
+        width * height
+    }
+
+    /// Addresses of the cells of the range, row by row
+    pub fn cells(&self) -> impl Iterator<Item = CellAddress> {
+        let CellRange(start, end) = *self;
+        (start.1..=end.1).flat_map(move |y| (start.0..=end.0).map(move |x| CellAddress(x, y)))
+    }
+}
+
+impl Evaluatable for CellRange {
+    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, CellError> {
+        let CellRange(top_left_cell, bot_right_cell) = self;
+
+        if top_left_cell.0 > bot_right_cell.0 || top_left_cell.1 > bot_right_cell.1 {
+            return Err(CellError::Ref(format!("Invalid range {}", self.to_string())));
+        }
+
+        let mut rows: Vec<Vec<Primitive>> = Vec::new();
+        for y in top_left_cell.1..=bot_right_cell.1 {
+            let mut row: Vec<Primitive> = Vec::new();
+            for x in top_left_cell.0..=bot_right_cell.0 {
+                let cell = CellAddress(x, y);
+                match environment.grid().get_cell(&cell) {
+                    Some(Ok(val)) => row.push(val.evaluate(environment)?),
+                    Some(Err(err)) => return Err(err.clone().propagate(environment.error_cell(cell))),
+                    None => row.push(Primitive::Empty),
+                }
+            }
+            rows.push(row);
+        }
+
+        Ok(Primitive::Array(rows))
+    }
+
+    fn references(&self) -> Vec<Reference> {
+        vec![Reference { sheet: None, range: *self }]
     }
 }
 
-/// Value of given cell
+impl ToString for CellRange {
+    fn to_string(&self) -> String {
+        format!("{}:{}", self.0.to_string(), self.1.to_string())
+    }
+}
+
+/// Value of given cell, with which of its column and row are absolute and where it was written
 #[derive(Debug, Clone)]
-pub struct CellValue(pub i32, pub i32);
+pub struct CellValue(pub i32, pub i32, pub Absolute, pub Span);
 
 impl Evaluatable for CellValue {
-    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, String> {
+    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, CellError> {
         let value = environment.grid().get_cell(&CellAddress(self.0, self.1));
         match value {
             Some(result) => match result {
                 Ok(val) => val.evaluate(environment),
-                Err(err) => Err(err.to_string()),
+                Err(err) => Err(err.clone().propagate(environment.error_cell(CellAddress(self.0, self.1)))),
+            }
+            None => Ok(Primitive::Empty),
+        }
+    }
+
+    fn references(&self) -> Vec<Reference> {
+        let adr = CellAddress(self.0, self.1);
+        vec![Reference { sheet: None, range: CellRange(adr, adr) }]
+    }
+
+    fn retarget(&mut self, offset: (i32, i32), edits: &mut Vec<(Span, String)>) -> bool {
+        let adr = CellAddress(self.0, self.1);
+        match adr.retarget(self.2, offset) {
+            Some(moved) => {
+                if moved != adr {
+                    (self.0, self.1) = (moved.0, moved.1);
+                    edits.push((self.3, self.to_string()));
+                }
+                true
+            }
+            None => {
+                edits.push((self.3, String::from("#REF!")));
+                false
             }
-            None => Err(format!("Value for cell ({}, {}) not found", self.0, self.1)),
         }
     }
 }
 
 impl ToString for CellValue {
     fn to_string(&self) -> String {
-        format!("({}, {})", self.0, self.1)
+        CellAddress(self.0, self.1).to_anchored_a1(self.2)
     }
 }
 
+/// Values of the cells of a range, with which of the columns and rows of its corners are absolute
+/// and where it was written
+#[derive(Debug, Clone)]
+pub struct RangeValue(pub CellRange, pub Absolute, pub Absolute, pub Span);
+
+impl Evaluatable for RangeValue {
+    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, CellError> {
+        self.0.evaluate(environment)
+    }
+
+    fn references(&self) -> Vec<Reference> {
+        self.0.references()
+    }
+
+    fn retarget(&mut self, offset: (i32, i32), edits: &mut Vec<(Span, String)>) -> bool {
+        match (self.0.0.retarget(self.1, offset), self.0.1.retarget(self.2, offset)) {
+            (Some(start), Some(end)) => {
+                if CellRange(start, end) != self.0 {
+                    self.0 = CellRange(start, end);
+                    edits.push((self.3, self.to_string()));
+                }
+                true
+            }
+            _ => {
+                edits.push((self.3, String::from("#REF!")));
+                false
+            }
+        }
+    }
+}
+
+impl ToString for RangeValue {
+    fn to_string(&self) -> String {
+        format!("{}:{}", self.0.0.to_anchored_a1(self.1), self.0.1.to_anchored_a1(self.2))
+    }
+}
+
+/// Block of cells read by an expression, on the sheet of the formula unless another sheet is named
+#[derive(Eq, Hash, PartialEq, Clone, Copy, Debug)]
+pub struct Reference {
+    pub sheet: Option<SheetId>,
+    pub range: CellRange,
+}
+
+/// Cell or range on a sheet named in the formula, such as Inputs![0, 0]
 #[derive(Debug)]
-pub enum Statistics {
-    Max(CellAddress, CellAddress),
-    Min(CellAddress, CellAddress),
-    Mean(CellAddress, CellAddress),
-    Sum(CellAddress, CellAddress),
+pub struct SheetReference {
+    pub sheet: SheetId,
+    /// Name of the sheet, as known when the formula was parsed
+    pub name: String,
+    pub reference: Box<dyn Evaluatable>,
+    /// Where the reference was written, including the name of the sheet
+    pub span: Span,
 }
 
-impl ToString for Statistics {
+impl Evaluatable for SheetReference {
+    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, CellError> {
+        match environment.on_sheet(self.sheet) {
+            Ok(sheet_environment) => self.reference.evaluate(&sheet_environment),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn references(&self) -> Vec<Reference> {
+        self.reference.references().into_iter()
+            .map(|reference| Reference { sheet: Some(self.sheet), range: reference.range })
+            .collect()
+    }
+
+    fn retarget(&mut self, offset: (i32, i32), edits: &mut Vec<(Span, String)>) -> bool {
+        let mut moved: Vec<(Span, String)> = Vec::new();
+        if self.reference.retarget(offset, &mut moved) {
+            edits.append(&mut moved);
+            true
+        } else {
+            edits.push((self.span, String::from("#REF!")));
+            false
+        }
+    }
+}
+
+impl ToString for SheetReference {
+    fn to_string(&self) -> String {
+        format!("{}!{}", self.name, self.reference.to_string())
+    }
+}
+
+/// Reference to cells that have been deleted, written as #REF!
+#[derive(Debug)]
+pub struct DeletedReference;
+
+impl Evaluatable for DeletedReference {
+    fn evaluate(&self, _environment: &Environment) -> Result<Primitive, CellError> {
+        Err(CellError::Ref(String::from("Reference to deleted cells")))
+    }
+
+    fn references(&self) -> Vec<Reference> {
+        Vec::new()
+    }
+}
+
+impl ToString for DeletedReference {
+    fn to_string(&self) -> String {
+        String::from("#REF!")
+    }
+}
+
+/// Expression that only evaluates the operands it needs, so errors in the others are not
+/// propagated
+#[derive(Debug)]
+pub enum Conditional {
+    /// Value of the second operand if the condition holds, otherwise value of the third
+    If(Box<dyn Evaluatable>, Box<dyn Evaluatable>, Box<dyn Evaluatable>),
+    /// Value of the first operand, or of the second if the first is an error
+    IfError(Box<dyn Evaluatable>, Box<dyn Evaluatable>),
+}
+
+impl ToString for Conditional {
     fn to_string(&self) -> String {
         match self {
-            Self::Max(cell1, cell2) => format!("max({}, {})", cell1.to_string(), cell2.to_string()),
-            Self::Min(cell1, cell2) => format!("min({}, {})", cell1.to_string(), cell2.to_string()),
-            Self::Mean(cell1, cell2) => format!("mean({}, {})", cell1.to_string(), cell2.to_string()),
-            Self::Sum(cell1, cell2) => format!("sum({}, {})", cell1.to_string(), cell2.to_string()),
+            Self::If(condition, then, otherwise) => format!("if({}, {}, {})", condition.to_string(), then.to_string(), otherwise.to_string()),
+            Self::IfError(val, fallback) => format!("iferror({}, {})", val.to_string(), fallback.to_string()),
         }
     }
 }
 
-impl Evaluatable for Statistics {
-    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, String> {
-        let top_left_cell: &'a CellAddress;
-        let bot_right_cell: &'a CellAddress;
+impl Evaluatable for Conditional {
+    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, CellError> {
+        match self {
+            Self::If(condition, then, otherwise) => {
+                match condition.evaluate(environment)? {
+                    Primitive::Boolean(true) => then.evaluate(environment),
+                    Primitive::Boolean(false) | Primitive::Empty => otherwise.evaluate(environment),
+                    val => Err(CellError::Value(format!("Condition must be Boolean but got {}", val.type_string()))),
+                }
+            }
+            Self::IfError(val, fallback) => {
+                match val.evaluate(environment) {
+                    Ok(val) => Ok(val),
+                    Err(_) => fallback.evaluate(environment),
+                }
+            }
+        }
+    }
 
+    fn references(&self) -> Vec<Reference> {
         match self {
-            Self::Max(val1, val2) => {
-                top_left_cell = val1;
-                bot_right_cell = val2;
+            Self::If(condition, then, otherwise) => {
+                let mut references = condition.references();
+                references.append(&mut then.references());
+                references.append(&mut otherwise.references());
+                references
             }
-            Self::Min(val1, val2) => {
-                top_left_cell = val1;
-                bot_right_cell = val2;
+            Self::IfError(val, fallback) => {
+                let mut references = val.references();
+                references.append(&mut fallback.references());
+                references
             }
-            Self::Mean(val1, val2) => {
-                top_left_cell = val1;
-                bot_right_cell = val2;
+        }
+    }
+
+    fn retarget(&mut self, offset: (i32, i32), edits: &mut Vec<(Span, String)>) -> bool {
+        match self {
+            Self::If(condition, then, otherwise) => {
+                retarget_operand(condition, offset, edits);
+                retarget_operand(then, offset, edits);
+                retarget_operand(otherwise, offset, edits);
             }
-            Self::Sum(val1, val2) => {
-                top_left_cell = val1;
-                bot_right_cell = val2;
+            Self::IfError(val, fallback) => {
+                retarget_operand(val, offset, edits);
+                retarget_operand(fallback, offset, edits);
             }
         }
+        true
+    }
 
-        if top_left_cell.0 > bot_right_cell.0 || top_left_cell.1 > bot_right_cell.1 {
-            Err(format!("Invalid Address for Statistics Function"))
-        } else {
-            let cells: Vec<CellAddress> = get_cells(top_left_cell, bot_right_cell);
-            let mut cell_vals: Vec<Primitive> = Vec::new();
-            
-            for cell in &cells {
-                let result = environment.grid().get_cell(&cell);
-
-                match result {
-                    Some(result) => {
-                        let evaluated_val = match result {
-                            Ok(val) => val.evaluate(environment),
-                            Err(err) => return Err(err.to_string()),
-                        };
-
-                        match evaluated_val {
-                            Ok(primitive_val) => {
-                                if !primitive_val.is_numeric() {
-                                    return Err(format!("Value in cell ({},{}) is not numeric", cell.0, cell.1));
-                                }
-
-                                cell_vals.push(primitive_val);
-                            }
-                            Err(e) => {
-                                return Err(e);
-                            }
-                        }
-                    }
-                    None => return Err(format!("Value for cell ({}, {}) not found", cell.0, cell.1)),
-                }
+    fn names(&self) -> Vec<String> {
+        match self {
+            Self::If(condition, then, otherwise) => {
+                let mut names = condition.names();
+                names.append(&mut then.names());
+                names.append(&mut otherwise.names());
+                names
             }
+            Self::IfError(val, fallback) => {
+                let mut names = val.names();
+                names.append(&mut fallback.names());
+                names
+            }
+        }
+    }
+}
+
+/// Name bound to a value by an enclosing let expression or lambda, or defined in the workbook
+#[derive(Debug, Clone)]
+pub struct Variable(pub String);
+
+impl ToString for Variable {
+    fn to_string(&self) -> String {
+        self.0.clone()
+    }
+}
+
+impl Evaluatable for Variable {
+    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, CellError> {
+        match environment.lookup(&self.0) {
+            Some(val) => val,
+            None => Err(CellError::Name(format!("Unknown name {}", self.0))),
+        }
+    }
+
+    fn references(&self) -> Vec<Reference> {
+        Vec::new()
+    }
+
+    fn names(&self) -> Vec<String> {
+        vec![self.0.clone()]
+    }
+}
+
+/// Names bound to values, each evaluated once, for use in the later values and in the body
+#[derive(Debug)]
+pub struct Let {
+    pub bindings: Vec<(String, Box<dyn Evaluatable>)>,
+    pub body: Box<dyn Evaluatable>,
+}
+
+impl ToString for Let {
+    fn to_string(&self) -> String {
+        let bindings: Vec<String> = self.bindings.iter()
+            .map(|(name, val)| format!("{}, {}", name, val.to_string()))
+            .collect();
+        format!("let({}, {})", bindings.join(", "), self.body.to_string())
+    }
+}
+
+impl Evaluatable for Let {
+    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, CellError> {
+        let mut scope: Option<Environment> = None;
+        for (name, val) in &self.bindings {
+            let current = scope.as_ref().unwrap_or(environment);
+            let val = val.evaluate(current)?;
+            scope = Some(current.with_variable(name, val));
+        }
+        self.body.evaluate(scope.as_ref().unwrap_or(environment))
+    }
+
+    fn references(&self) -> Vec<Reference> {
+        let mut references: Vec<Reference> = Vec::new();
+        for (_, val) in &self.bindings {
+            references.append(&mut val.references());
+        }
+        references.append(&mut self.body.references());
+        references
+    }
+
+    fn retarget(&mut self, offset: (i32, i32), edits: &mut Vec<(Span, String)>) -> bool {
+        for (_, val) in &mut self.bindings {
+            retarget_operand(val, offset, edits);
+        }
+        retarget_operand(&mut self.body, offset, edits);
+        true
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for (_, val) in &self.bindings {
+            names.append(&mut val.names());
+        }
+        names.append(&mut self.body.names());
+        names
+    }
+}
+
+/// Array written out in a formula, row by row
+#[derive(Debug)]
+pub struct ArrayLiteral(pub Vec<Vec<Box<dyn Evaluatable>>>);
+
+impl ToString for ArrayLiteral {
+    fn to_string(&self) -> String {
+        let rows: Vec<String> = self.0.iter()
+            .map(|row| row.iter().map(|val| val.to_string()).collect::<Vec<String>>().join(", "))
+            .collect();
+        format!("{{{}}}", rows.join("; "))
+    }
+}
 
-            match self {
-                Self::Max(_, _) => max(&cell_vals, environment),
-                Self::Min(_, _) => min(&cell_vals, environment),
-                Self::Mean(_, _) => mean(&cell_vals),
-                Self::Sum(_, _) => Ok(Primitive::Float(sum(&cell_vals))),
+impl Evaluatable for ArrayLiteral {
+    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, CellError> {
+        let mut rows: Vec<Vec<Primitive>> = Vec::new();
+        for row in &self.0 {
+            let mut vals: Vec<Primitive> = Vec::new();
+            for element in row {
+                match element.evaluate(environment)? {
+                    Primitive::Array(_) => return Err(CellError::Value(String::from("Arrays cannot be nested"))),
+                    val => vals.push(val),
+                }
             }
+            rows.push(vals);
+        }
+        Ok(Primitive::Array(rows))
+    }
+
+    fn references(&self) -> Vec<Reference> {
+        self.0.iter().flatten().flat_map(|element| element.references()).collect()
+    }
+
+    fn retarget(&mut self, offset: (i32, i32), edits: &mut Vec<(Span, String)>) -> bool {
+        for element in self.0.iter_mut().flatten() {
+            retarget_operand(element, offset, edits);
         }
+        true
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.0.iter().flatten().flat_map(|element| element.names()).collect()
     }
 }
 
-fn mean<'a>(cell_vals: &Vec<Primitive>) -> Result<Primitive, String> {
-    Ok(Primitive::Float(sum(&cell_vals) / cell_vals.len() as f32))
+/// Call of a function from the registry in the environment
+#[derive(Debug)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: Vec<Box<dyn Evaluatable>>,
 }
 
-fn sum(cell_vals: &Vec<Primitive>) -> f32 {
-    let mut total = 0.0;
-    for val in cell_vals {
-        total += coerce_to_float(&val).unwrap().get_float_value().unwrap();
+impl ToString for FunctionCall {
+    fn to_string(&self) -> String {
+        let arguments: Vec<String> = self.arguments.iter().map(|argument| argument.to_string()).collect();
+        format!("{}({})", self.name, arguments.join(", "))
     }
-    total
 }
 
-fn max<'a>(vals: &Vec<Primitive>, _environment: &'a Environment) -> Result<Primitive, String> {
-    let mut max_val = Primitive::Integer(i32::MIN);
-    for val in vals {
-        if coerce_to_float(&val).unwrap().get_float_value() > coerce_to_float(&max_val).unwrap().get_float_value() {
-            max_val = val.clone();
+impl Evaluatable for FunctionCall {
+    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, CellError> {
+        if let Some(function) = environment.functions().get(&self.name) {
+            let mut values: Vec<ArgumentValue> = Vec::new();
+            for argument in &self.arguments {
+                values.push(ArgumentValue::Value(argument.evaluate(environment)?));
+            }
+            return function.call(&values);
+        }
+
+        // Otherwise the name has to hold a lambda
+        let closure = match environment.lookup(&self.name) {
+            Some(Ok(Primitive::Lambda(closure))) => closure,
+            Some(Ok(val)) => return Err(CellError::Value(format!("{} is not a function but a value of type {}", self.name, val.type_string()))),
+            Some(Err(err)) => return Err(err),
+            None => return Err(CellError::Name(format!("Unknown function {}", self.name))),
+        };
+
+        let mut values: Vec<Primitive> = Vec::new();
+        for argument in &self.arguments {
+            values.push(argument.evaluate(environment)?);
         }
+        closure.call(&self.name, values, environment)
     }
-    Ok(max_val)
+
+    fn references(&self) -> Vec<Reference> {
+        let mut references: Vec<Reference> = Vec::new();
+        for argument in &self.arguments {
+            references.append(&mut argument.references());
+        }
+        references
+    }
+
+    fn retarget(&mut self, offset: (i32, i32), edits: &mut Vec<(Span, String)>) -> bool {
+        for argument in &mut self.arguments {
+            retarget_operand(argument, offset, edits);
+        }
+        true
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = vec![self.name.clone()];
+        for argument in &self.arguments {
+            names.append(&mut argument.names());
+        }
+        names
+    }
+}
+
+/// Function written in a formula, which evaluates to a closure over the variables in scope
+#[derive(Debug)]
+pub struct Lambda {
+    pub parameters: Vec<String>,
+    pub body: Rc<dyn Evaluatable>,
 }
 
-fn min<'a>(vals: &Vec<Primitive>, _environment: &'a Environment) -> Result<Primitive, String> {
-    let mut min_val = Primitive::Integer(i32::MAX);
-    for val in vals {
-        if coerce_to_float(&val).unwrap().get_float_value() < coerce_to_float(&min_val).unwrap().get_float_value() {
-            min_val = val.clone();
+impl ToString for Lambda {
+    fn to_string(&self) -> String {
+        let mut parts: Vec<String> = self.parameters.clone();
+        parts.push(self.body.to_string());
+        format!("lambda({})", parts.join(", "))
+    }
+}
+
+impl Evaluatable for Lambda {
+    fn evaluate<'a>(&'a self, environment: &'a Environment) -> Result<Primitive, CellError> {
+        Ok(Primitive::Lambda(Rc::new(Closure {
+            parameters: self.parameters.clone(),
+            body: self.body.clone(),
+            captured: environment.variables().clone(),
+        })))
+    }
+
+    fn references(&self) -> Vec<Reference> {
+        self.body.references()
+    }
+
+    fn retarget(&mut self, offset: (i32, i32), edits: &mut Vec<(Span, String)>) -> bool {
+        // The body is only shared once the lambda is evaluated
+        if let Some(body) = Rc::get_mut(&mut self.body) {
+            if !body.retarget(offset, edits) {
+                self.body = Rc::new(DeletedReference);
+            }
         }
+        true
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.body.names()
+    }
+}
+
+/// Value of a lambda, along with the variables that were in scope where it was written
+#[derive(Debug)]
+pub struct Closure {
+    parameters: Vec<String>,
+    body: Rc<dyn Evaluatable>,
+    captured: HashMap<String, Primitive>,
+}
+
+impl ToString for Closure {
+    fn to_string(&self) -> String {
+        let mut parts: Vec<String> = self.parameters.clone();
+        parts.push(self.body.to_string());
+        parts.join(", ")
     }
-    Ok(min_val)
 }
 
-fn get_cells(top_left_cell: &CellAddress, bot_right_cell: &CellAddress) -> Vec<CellAddress> {
-    let mut cells: Vec<CellAddress> = Vec::new();
+impl Closure {
+    /// Evaluates the body with the parameters bound to the arguments, on top of the captured
+    /// variables. The name is the one the closure was called by, for error messages
+    pub fn call(&self, name: &str, arguments: Vec<Primitive>, environment: &Environment) -> Result<Primitive, CellError> {
+        if arguments.len() != self.parameters.len() {
+            return Err(CellError::Value(format!("Function {} expects {} argument(s) but got {}", name, self.parameters.len(), arguments.len())));
+        }
 
-    for y in top_left_cell.1..=bot_right_cell.1 {
-        for x in top_left_cell.0..=bot_right_cell.0 {
-            cells.push(CellAddress(x, y));
+        let mut variables = self.captured.clone();
+        for (parameter, val) in self.parameters.iter().zip(arguments) {
+            variables.insert(parameter.to_lowercase(), val);
         }
+        self.body.evaluate(&environment.call_scope(variables)?)
     }
+}
 
-    cells
+/// Number of rows and columns in an array
+fn array_size(rows: &[Vec<Primitive>]) -> (usize, usize) {
+    (rows.len(), rows.first().map_or(0, |row| row.len()))
 }
 
-fn get_results<'a>(operation: &'a Operation, environment: &'a Environment) -> (Result<Primitive, String>, Option<Result<Primitive, String>>) {
-    let val1: Result<Primitive, String>;
-    let val2: Option<Result<Primitive, String>>;
+fn get_results<'a>(operation: &'a Operation, environment: &'a Environment) -> (Result<Primitive, CellError>, Option<Result<Primitive, CellError>>) {
+    let val1: Result<Primitive, CellError>;
+    let val2: Option<Result<Primitive, CellError>>;
 
     match operation {
         Operation::Add(v1, v2) => {
@@ -473,13 +1156,8 @@ fn get_results<'a>(operation: &'a Operation, environment: &'a Environment) -> (R
             val2 = Some(v2.evaluate(environment));
         }
 
-        Operation::LogicalAnd(v1, v2) => {
-            val1 = v1.evaluate(environment);
-            val2 = Some(v2.evaluate(environment));
-        }
-        Operation::LogicalOr(v1, v2) => {
-            val1 = v1.evaluate(environment);
-            val2 = Some(v2.evaluate(environment));
+        Operation::LogicalAnd(_, _) | Operation::LogicalOr(_, _) => {
+            panic!("Unexpected Short-Circuiting Operation")
         }
         Operation::LogicalNot(v1) => {
             val1 = v1.evaluate(environment);
@@ -552,74 +1230,58 @@ fn get_results<'a>(operation: &'a Operation, environment: &'a Environment) -> (R
 }
 
 fn unpack_results<'a> (
-    result1: Result<Primitive, String>,
-    result2: Option<Result<Primitive, String>>,
-) -> Result<(Primitive, Option<Primitive>), String> {
-    let result1_unpacked: Option<Primitive>;
-    let result2_unpacked: Option<Option<Primitive>>;
-    let mut error = String::from("");
-
-    match result1 {
-        Ok(v) => result1_unpacked = Some(v),
-        Err(e) => {
-            error = e;
-            result1_unpacked = None;
-        }
-    }
-
-    match result2 {
-        Some(result) => match result {
-            Ok(v) => result2_unpacked = Some(Some(v)),
-            Err(e) => {
-                if error.is_empty() {
-                    error = e;
-                }
-                result2_unpacked = None;
-            }
-        },
-        None => result2_unpacked = None,
+    result1: Result<Primitive, CellError>,
+    result2: Option<Result<Primitive, CellError>>,
+) -> Result<(Primitive, Option<Primitive>), CellError> {
+    match (result1, result2) {
+        (Err(e), _) => Err(e),
+        (Ok(_), Some(Err(e))) => Err(e),
+        (Ok(v1), Some(Ok(v2))) => Ok((v1, Some(v2))),
+        (Ok(v1), None) => Ok((v1, None)),
     }
-
-    let result: Result<(Primitive, Option<Primitive>), String>;
-
-    if error.is_empty() {
-        result =  Ok((result1_unpacked.unwrap(), result2_unpacked.unwrap_or_else(|| {
-            None
-        })));
-    } else {
-        result = Err(error)
-    }
-
-    result
 }
 
-fn arithmetic<'a>(operation: &Operation, val1: &Primitive, val2: &Primitive) -> Result<Primitive, String> {
+fn arithmetic<'a>(operation: &Operation, val1: &Primitive, val2: &Primitive) -> Result<Primitive, CellError> {
     if val1.is_integer() && val2.is_integer() {
         let v1 = val1.get_int_value().unwrap();
         let v2 = val2.get_int_value().unwrap();
 
-        match operation {
-            Operation::Add(_, _) => Ok(Primitive::Integer(v1 + v2)),
-            Operation::Subtract(_, _) => Ok(Primitive::Integer(v1 - v2)),
-            Operation::Multiply(_, _) => Ok(Primitive::Integer(v1 * v2)),
+        let result = match operation {
+            Operation::Add(_, _) => v1.checked_add(v2),
+            Operation::Subtract(_, _) => v1.checked_sub(v2),
+            Operation::Multiply(_, _) => v1.checked_mul(v2),
             Operation::Divide(_, _) => {
                 if v2 == 0 {
-                    Err(format!("Division by 0"))
-                } else {
-                    Ok(Primitive::Integer(v1 / v2))
+                    return Err(CellError::DivZero);
+                }
+                v1.checked_div(v2)
+            }
+            Operation::Modulus(_, _) => {
+                if v2 == 0 {
+                    return Err(CellError::DivZero);
                 }
+                v1.checked_rem(v2)
             }
-            Operation::Modulus(_, _) => Ok(Primitive::Integer(v1 % v2)),
             Operation::Power(_, _) => {
                 if v2 < 0 {
-                    Err(format!("Integer exponent cannot be less than 0 for integer base"))
+                    return Err(CellError::Num(String::from("Integer exponent cannot be less than 0 for integer base")));
                 } else if v1 == 0 && v2 == 0 {
-                    Err(format!("Cannot calculate 0 ^ 0"))
-                } else {
-                    Ok(Primitive::Integer(v1.pow(v2 as u32)))
+                    return Err(CellError::Num(String::from("Cannot calculate 0 ^ 0")));
+                }
+                match (u32::try_from(v2), v1) {
+                    (Ok(exponent), _) => v1.checked_pow(exponent),
+                    // Exponents too large for u32 only have a result for these bases
+                    (Err(_), 0 | 1) => Some(v1),
+                    (Err(_), -1) => Some(if v2 % 2 == 0 { 1 } else { -1 }),
+                    (Err(_), _) => None,
                 }
             }
             _ => panic!("Unexpected Arithmetic Operation"),
+        };
+
+        match result {
+            Some(val) => Ok(Primitive::Integer(val)),
+            None => Err(CellError::Overflow),
         }
     } else if val1.is_numeric() && val2.is_numeric() { // i.e. Because of the previous check, if
         // both are numeric, one must be a float
@@ -631,16 +1293,16 @@ fn arithmetic<'a>(operation: &Operation, val1: &Primitive, val2: &Primitive) ->
             Operation::Subtract(_, _) => Ok(Primitive::Float(v1 - v2)),
             Operation::Multiply(_, _) => Ok(Primitive::Float(v1 * v2)),
             Operation::Divide(_, _) => {
-                if v2 + 1.0 < 1.0 + f32::EPSILON && v2 > -f32::EPSILON {
-                    Err(format!("Division by 0"))
+                if v2 + 1.0 < 1.0 + f64::EPSILON && v2 > -f64::EPSILON {
+                    Err(CellError::DivZero)
                 } else {
                     Ok(Primitive::Float(v1 / v2))
                 }
             }
             Operation::Modulus(_, _) => Ok(Primitive::Float(v1 % v2)),
             Operation::Power(_, _) => {
-                if v1 + 1.0 < 1.0 + f32::EPSILON  && v2 + 1.0 < 1.0 + f32::EPSILON {
-                    Err(format!("Cannot calculate 0 ^ 0"))
+                if v1 + 1.0 < 1.0 + f64::EPSILON  && v2 + 1.0 < 1.0 + f64::EPSILON {
+                    Err(CellError::Num(String::from("Cannot calculate 0 ^ 0")))
                 } else {
                     Ok(Primitive::Float(v1.powf(v2)))
                 }
@@ -652,7 +1314,7 @@ fn arithmetic<'a>(operation: &Operation, val1: &Primitive, val2: &Primitive) ->
     }
 }
 
-fn logic<'a>(operation: &Operation, val1: &Primitive, val2_option: &Option<Primitive>) -> Result<Primitive, String> {
+fn logic<'a>(operation: &Operation, val1: &Primitive, val2_option: &Option<Primitive>) -> Result<Primitive, CellError> {
     let val2 = val2_option.as_ref().unwrap_or_else(|| {
         &Primitive::Boolean(false)
     });
@@ -672,12 +1334,12 @@ fn logic<'a>(operation: &Operation, val1: &Primitive, val2_option: &Option<Primi
     })
 }
 
-fn bitwise<'a>(operation: &Operation, val1: &Primitive, val2_option: &Option<Primitive>) -> Result<Primitive, String> {
+fn bitwise<'a>(operation: &Operation, val1: &Primitive, val2_option: &Option<Primitive>) -> Result<Primitive, CellError> {
     let val2 = val2_option.as_ref().unwrap_or_else(|| {
         &Primitive::Integer(0)
     });
 
-    if !val1.is_integer() || val2.is_boolean() {
+    if !val1.is_integer() || !val2.is_integer() {
         return type_mismatch_error(val1, val2_option, operation);
     }
 
@@ -689,21 +1351,43 @@ fn bitwise<'a>(operation: &Operation, val1: &Primitive, val2_option: &Option<Pri
         Operation::BitwiseAnd(_, _) => Primitive::Integer(v1 & v2),
         Operation::BitwiseOr(_, _) => Primitive::Integer(v1 | v2),
         Operation::BitwiseXor(_, _) => Primitive::Integer(v1 ^ v2),
-        Operation::LeftShift(_, _) => Primitive::Integer(v1 << v2),
-        Operation::RightShift(_, _) => Primitive::Integer(v1 >> v2),
+        Operation::LeftShift(_, _) => Primitive::Integer(left_shift(v1, shift_amount(v2)?)?),
+        Operation::RightShift(_, _) => Primitive::Integer(v1 >> shift_amount(v2)?),
         _ => panic!("Unexpected Bitwise Type"),
     })
 }
 
-fn equality<'a>(operation: &Operation, val1: &Primitive, val2: &Primitive) -> Result<Primitive, String> {
+/// Checks that an integer can be shifted by the given number of bits
+fn shift_amount(bits: i64) -> Result<u32, CellError> {
+    if (0..i64::BITS as i64).contains(&bits) {
+        Ok(bits as u32)
+    } else {
+        Err(CellError::InvalidShift(bits))
+    }
+}
+
+/// Shifts an integer left, failing if bits are shifted out or into the sign bit
+fn left_shift(val: i64, bits: u32) -> Result<i64, CellError> {
+    let shifted = val << bits;
+    if shifted >> bits == val {
+        Ok(shifted)
+    } else {
+        Err(CellError::Overflow)
+    }
+}
+
+fn equality<'a>(operation: &Operation, val1: &Primitive, val2: &Primitive) -> Result<Primitive, CellError> {
     if !val1.type_string().eq(&val2.type_string()) {
         Ok(Primitive::Boolean(false))
     } else {
         let mut result = match val1 {
             Primitive::Integer(v1) => Primitive::Boolean(v1.clone() == val2.get_int_value().unwrap()),
-            Primitive::Float(v1) => Primitive::Boolean((v1 - val2.get_float_value().unwrap()).abs() < f32::EPSILON),
+            Primitive::Float(v1) => Primitive::Boolean((v1 - val2.get_float_value().unwrap()).abs() < f64::EPSILON),
             Primitive::String(v1) => Primitive::Boolean(v1.eq(&val2.get_string_value().unwrap())),
             Primitive::Boolean(v1) => Primitive::Boolean(v1 == &val2.get_boolean_value().unwrap()),
+            Primitive::Empty => Primitive::Boolean(true),
+            Primitive::Array(_) => panic!("Unexpected Array in Equality Operation"),
+            Primitive::Lambda(v1) => Primitive::Boolean(matches!(val2, Primitive::Lambda(v2) if Rc::ptr_eq(v1, v2))),
         };
 
         if let Operation::NotEquals(_, _) = operation {
@@ -714,7 +1398,7 @@ fn equality<'a>(operation: &Operation, val1: &Primitive, val2: &Primitive) -> Re
     }
 }
 
-fn relation<'a>(operation: &Operation, val1: &Primitive, val2: &Primitive) -> Result<Primitive, String> {
+fn relation<'a>(operation: &Operation, val1: &Primitive, val2: &Primitive) -> Result<Primitive, CellError> {
     if val1.is_integer() && val2.is_integer() {
         let v1 = val1.get_int_value().unwrap();
         let v2 = val2.get_int_value().unwrap();
@@ -733,9 +1417,9 @@ fn relation<'a>(operation: &Operation, val1: &Primitive, val2: &Primitive) -> Re
 
         Ok(match operation {
             Operation::LessThan(_, _) => Primitive::Boolean(v1 < v2),
-            Operation::LessThanOrEqual(_, _) => Primitive::Boolean((v1 < v2) || (v1 - v2).abs() < f32::EPSILON),
+            Operation::LessThanOrEqual(_, _) => Primitive::Boolean((v1 < v2) || (v1 - v2).abs() < f64::EPSILON),
             Operation::GreaterThan(_, _) => Primitive::Boolean(v1 > v2),
-            Operation::GreaterThanOrEqual(_, _) => Primitive::Boolean((v1 < v2) || (v1 - v2).abs() < f32::EPSILON),
+            Operation::GreaterThanOrEqual(_, _) => Primitive::Boolean((v1 > v2) || (v1 - v2).abs() < f64::EPSILON),
             _ => panic!("Unexpected Relational Operation"),
         })
     } else {
@@ -743,26 +1427,31 @@ fn relation<'a>(operation: &Operation, val1: &Primitive, val2: &Primitive) -> Re
     }
 }
 
-fn cast_to_integer<'a>(float: &Primitive) -> Result<Primitive, String> {
+fn cast_to_integer<'a>(float: &Primitive) -> Result<Primitive, CellError> {
     if let Primitive::Float(val) = float {
-        Ok(Primitive::Integer(val.clone() as i32))
+        // i64::MAX as f64 rounds up to 2^63, so the upper bound is exclusive
+        if val.is_finite() && *val >= i64::MIN as f64 && *val < i64::MAX as f64 {
+            Ok(Primitive::Integer(val.clone() as i64))
+        } else {
+            Err(CellError::Overflow)
+        }
     } else {
-        Err(format!("Cannot cast type: {}", float.type_string()))
+        Err(CellError::Value(format!("Cannot cast type: {}", float.type_string())))
     }
 }
 
-fn cast_to_float<'a>(integer: &Primitive) -> Result<Primitive, String> {
+fn cast_to_float<'a>(integer: &Primitive) -> Result<Primitive, CellError> {
     if let Primitive::Integer(val) = integer {
-        Ok(Primitive::Float(val.clone() as f32))
+        Ok(Primitive::Float(val.clone() as f64))
     } else {
-        Err(format!("Cannot cast type: {}", integer.type_string()))
+        Err(CellError::Value(format!("Cannot cast type: {}", integer.type_string())))
     }
 }
 
-fn coerce_to_float<'a>(num: &Primitive) -> Result<Primitive, String> {
+fn coerce_to_float<'a>(num: &Primitive) -> Result<Primitive, CellError> {
     match num {
         Primitive::Float(val) => Ok(Primitive::Float(val.clone())),
         Primitive::Integer(_) => cast_to_float(num),
-        _ => Err(format!("Cannot coerce type {} to float", num.type_string()))
+        _ => Err(CellError::Value(format!("Cannot coerce type {} to float", num.type_string())))
     }
 }
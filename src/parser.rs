@@ -1,35 +1,85 @@
 // use std::fmt::format;
+use std::{collections::HashMap, rc::Rc};
 
-use crate::{lexer::{Token, TokenType}, model::{Evaluatable, Primitive, Operation, Statistics, CellAddress, CellValue}};
+use crate::{lexer::{Token, TokenType}, error::CellError, functions::{ArgumentKind, FunctionRegistry}, model::{Evaluatable, Primitive, Operation, ArrayLiteral, Conditional, DeletedReference, FunctionCall, Lambda, Let, Variable, Absolute, CellAddress, CellRange, CellValue, RangeValue, Reference, SheetId, SheetReference, Span}};
 
-pub fn parse(tokens: Vec<Token>) -> Result<Box<dyn Evaluatable>, String> {
-    Parser::new(tokens).parse()
+/// Names of the functions built into the language, which only evaluate some of their arguments
+pub const SPECIAL_FORMS: [&str; 4] = ["if", "iferror", "lambda", "let"];
+
+/// Cells and ranges defined in the workbook under a name, keyed by lowercase name
+pub type NamedRanges = HashMap<String, Reference>;
+
+/// Sheets of the workbook that formulas can name, keyed by lowercase name
+pub type SheetNames = HashMap<String, SheetId>;
+
+/// Parses a formula, using the registry to decide how the arguments of each function call are
+/// parsed. Names of cells and ranges are replaced by the cells they stand for, unless a let or
+/// lambda binds the same name
+pub fn parse(tokens: Vec<Token>, functions: &FunctionRegistry, ranges: &NamedRanges, sheets: &SheetNames) -> Result<Box<dyn Evaluatable>, CellError> {
+    Parser::new(tokens, functions, ranges, sheets).parse()
+}
+
+/// Cell or range of cells that a formula consists of, if it consists of nothing else, such as
+/// [0, 0]:[0, 9] or Inputs![0, 0]
+pub fn parse_reference(tokens: Vec<Token>, functions: &FunctionRegistry, sheets: &SheetNames) -> Option<Reference> {
+    let ranges = NamedRanges::new();
+    let mut parser = Parser::new(tokens, functions, &ranges, sheets);
+
+    let (left, _, _) = parser.cell_reference().ok()?;
+    let reference = if parser.has(TokenType::Colon) {
+        parser.capture();
+        let (right, _, _) = parser.cell_reference().ok()?;
+        let sheet = parser.range_sheet(left.sheet, right.sheet).ok()?;
+        Reference { sheet, range: CellRange(left.range.0, right.range.1) }
+    } else {
+        left
+    };
+
+    if parser.index == parser.tokens.len() {
+        Some(reference)
+    } else {
+        None
+    }
 }
 
 struct Parser<'a> {
     tokens: Vec<Token<'a>>,
     index: usize,
+    functions: &'a FunctionRegistry,
+    ranges: &'a NamedRanges,
+    sheets: &'a SheetNames,
+    /// Lowercase names bound by the enclosing let expressions and lambdas, which hide names of
+    /// cells and ranges
+    bound: Vec<String>,
 }
 
-impl Parser<'_> {
-    fn new(tokens: Vec<Token>) -> Parser {
-        Parser { tokens: tokens, index: 0 }
+impl<'a> Parser<'a> {
+    fn new(tokens: Vec<Token<'a>>, functions: &'a FunctionRegistry, ranges: &'a NamedRanges, sheets: &'a SheetNames) -> Parser<'a> {
+        Parser { tokens: tokens, index: 0, functions, ranges, sheets, bound: Vec::new() }
     }
 
-    fn parse(&mut self) -> Result<Box<dyn Evaluatable>, String> {
-        self.expression()
+    fn parse(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
+        let expression = self.expression()?;
+        match self.tokens.get(self.index) {
+            Some(token) => Err(self.error(format!("Unexpected Token {}", token.text))),
+            None => Ok(expression),
+        }
     }
 
     fn has(&self, token_type: TokenType) -> bool {
         self.index < self.tokens.len() && self.tokens.get(self.index).unwrap().token_type == token_type
     }
 
-    fn cur_token_index(&self) -> usize {
-        if self.index > self.tokens.len() {
-            self.tokens[self.index].start
-        } else {
-            self.tokens[self.tokens.len() - 1].start
-        }
+    /// Parse error spanning the current token, or the end of the input once every token is consumed
+    fn error(&self, message: String) -> CellError {
+        let (start, end) = match self.tokens.get(self.index) {
+            Some(token) => (token.start, token.end),
+            None => match self.tokens.last() {
+                Some(token) => (token.end + 1, token.end + 1),
+                None => (0, 0),
+            },
+        };
+        CellError::Parse { message, start, end }
     }
 
     fn capture(&mut self) -> Token {
@@ -38,11 +88,11 @@ impl Parser<'_> {
         return token.clone();
     }
 
-    fn expression(&mut self) -> Result<Box<dyn Evaluatable>, String> {
+    fn expression(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
         self.logical_or()
     }
 
-    fn logical_or(&mut self) -> Result<Box<dyn Evaluatable>, String> {
+    fn logical_or(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
         let left_result = self.logical_and();
         let mut left: Box<dyn Evaluatable>;
 
@@ -66,7 +116,7 @@ impl Parser<'_> {
         Ok(left)
     }
     
-    fn logical_and(&mut self) -> Result<Box<dyn Evaluatable>, String> {
+    fn logical_and(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
         let left_result = self.bitwise_or();
         let mut left: Box<dyn Evaluatable>;
 
@@ -91,7 +141,7 @@ impl Parser<'_> {
 
     }
 
-    fn bitwise_or(&mut self) -> Result<Box<dyn Evaluatable>, String> {
+    fn bitwise_or(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
         let left_result = self.bitwise_xor();
         let mut left: Box<dyn Evaluatable>;
 
@@ -117,7 +167,7 @@ impl Parser<'_> {
 
     }
 
-    fn bitwise_xor(&mut self) -> Result<Box<dyn Evaluatable>, String> {
+    fn bitwise_xor(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
         let left_result = self.bitwise_and();
         let mut left: Box<dyn Evaluatable>;
 
@@ -143,7 +193,7 @@ impl Parser<'_> {
 
     }
 
-    fn bitwise_and(&mut self) -> Result<Box<dyn Evaluatable>, String> {
+    fn bitwise_and(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
         let left_result = self.equality();
         let mut left: Box<dyn Evaluatable>;
 
@@ -167,7 +217,7 @@ impl Parser<'_> {
         Ok(left)
     }
 
-    fn equality(&mut self) -> Result<Box<dyn Evaluatable>, String> {
+    fn equality(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
         let left_result = self.comparison();
         let mut left: Box<dyn Evaluatable>;
 
@@ -202,7 +252,7 @@ impl Parser<'_> {
         Ok(left)
     }
 
-    fn comparison(&mut self) -> Result<Box<dyn Evaluatable>, String> {
+    fn comparison(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
         let left_result = self.shift();
         let mut left: Box<dyn Evaluatable>;
 
@@ -257,7 +307,7 @@ impl Parser<'_> {
         Ok(left)
     }
 
-    fn shift(&mut self) -> Result<Box<dyn Evaluatable>, String> {
+    fn shift(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
         let left_result = self.additive();
         let mut left: Box<dyn Evaluatable>;
 
@@ -292,7 +342,7 @@ impl Parser<'_> {
         Ok(left)
     }
 
-    fn additive(&mut self) -> Result<Box<dyn Evaluatable>, String> {
+    fn additive(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
         let left_result = self.multiplicative();
         let mut left: Box<dyn Evaluatable>;
 
@@ -328,7 +378,7 @@ impl Parser<'_> {
         Ok(left)
     }
 
-    fn multiplicative(&mut self) -> Result<Box<dyn Evaluatable>, String> {
+    fn multiplicative(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
         let left_result = self.power();
         let mut left: Box<dyn Evaluatable>;
 
@@ -374,7 +424,7 @@ impl Parser<'_> {
         Ok(left)
     }
     
-    fn power(&mut self) -> Result<Box<dyn Evaluatable>, String> {
+    fn power(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
         let left_result = self.unary();
         let mut left: Box<dyn Evaluatable>;
 
@@ -398,7 +448,7 @@ impl Parser<'_> {
         Ok(left)
     }
     
-    fn unary(&mut self) -> Result<Box<dyn Evaluatable>, String> {
+    fn unary(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
         if self.has(TokenType::LogicalNot) {
             self.capture();
             match self.unary() {
@@ -424,57 +474,196 @@ impl Parser<'_> {
                 Err(err) => Err(err),
             }
         } else {
-            self.statistics()
+            self.call()
         }
     }
 
-    fn statistics(&mut self) -> Result<Box<dyn Evaluatable>, String> {
-        if self.has(TokenType::Max) || self.has(TokenType::Mean) || self.has(TokenType::Min) || self.has(TokenType::Sum) {
-            let function = self.tokens[self.index].token_type;
-            let left: CellAddress;
-            let right: CellAddress;
+    fn call(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
+        if self.has(TokenType::Identifier) && self.tokens.get(self.index + 1).map(|token| token.token_type) == Some(TokenType::OpenParenthesis) {
+            let name = self.capture().text.to_string();
+            if name.eq_ignore_ascii_case("let") {
+                return self.let_expression();
+            } else if name.eq_ignore_ascii_case("lambda") {
+                return self.lambda();
+            }
+
+            let function = self.functions.get(&name);
+            let mut arguments: Vec<Box<dyn Evaluatable>> = Vec::new();
             self.capture();
-            if self.has(TokenType::OpenParenthesis) {
-                self.capture();
-                let left_cell = self.cell_address();
-                match left_cell {
-                    Ok(val) => left = val,
-                    Err(val) => return Err(val),
-                }
 
-                if self.has(TokenType::Comma) {
-                    self.capture();
-                    let right_cell = self.cell_address();
-                    match right_cell {
-                        Ok(val) => right = val,
-                        Err(val) => return Err(val)
+            if !self.has(TokenType::CloseParenthesis) {
+                loop {
+                    // Arguments to unknown functions are parsed as values, and the call fails once
+                    // it is evaluated
+                    let kind = match function {
+                        Some(function) => function.parameter_kind(arguments.len()),
+                        None => ArgumentKind::Value,
+                    };
+
+                    match self.argument(kind) {
+                        Ok(val) => arguments.push(val),
+                        Err(val) => return Err(val),
                     }
 
-                    if self.has(TokenType::CloseParenthesis) {
+                    if self.has(TokenType::Comma) {
                         self.capture();
                     } else {
-                        return Err(format!("Missing Closing Parenthesis at {}", self.index))
+                        break;
                     }
-                } else {
-                    return Err(format!("Missing Comma at {}", self.index))
                 }
-            } else {
-                return Err(format!("Missing Open Parenthesis at {}", self.index))
             }
 
-            return match function {
-                TokenType::Max => Ok(Box::new(Statistics::Max(left, right))),
-                TokenType::Mean => Ok(Box::new(Statistics::Mean(left, right))),
-                TokenType::Min => Ok(Box::new(Statistics::Min(left, right))),
-                TokenType::Sum => Ok(Box::new(Statistics::Sum(left, right))),
-                _ => Err(format!("Incorrect function type"))
+            if !self.has(TokenType::CloseParenthesis) {
+                return Err(self.error(String::from("Missing Closing Parenthesis")))
+            }
+
+            // Conditionals are built into the language rather than the registry, as only some of
+            // their arguments are evaluated
+            let mut operands = arguments.into_iter();
+            let call: Box<dyn Evaluatable> = match (name.to_lowercase().as_str(), operands.len()) {
+                ("if", 2 | 3) => {
+                    let condition = operands.next().unwrap();
+                    let then = operands.next().unwrap();
+                    let otherwise = operands.next().unwrap_or_else(|| Box::new(Primitive::Boolean(false)));
+                    Box::new(Conditional::If(condition, then, otherwise))
+                }
+                ("if", count) => return Err(self.error(format!("Function if expects 2 or 3 argument(s) but got {count}"))),
+                ("iferror", 2) => Box::new(Conditional::IfError(operands.next().unwrap(), operands.next().unwrap())),
+                ("iferror", count) => return Err(self.error(format!("Function iferror expects 2 argument(s) but got {count}"))),
+                (_, count) => {
+                    if let Some(message) = function.and_then(|function| function.arity_error(count)) {
+                        return Err(self.error(message));
+                    }
+                    Box::new(FunctionCall { name, arguments: operands.collect() })
+                }
             };
+
+            self.capture();
+            return Ok(call);
         }
 
         self.atom()
     }
 
-    fn atom(&mut self) -> Result<Box<dyn Evaluatable>, String> {
+    /// Bindings of names to values followed by the body they are used in, such as
+    /// let(x, 2, y, x * 3, x + y)
+    fn let_expression(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
+        let mut bindings: Vec<(String, Box<dyn Evaluatable>)> = Vec::new();
+        let bound = self.bound.len();
+        self.capture();
+
+        // A name is always followed by a comma, which tells it apart from a body made of a name
+        while self.has(TokenType::Identifier) && self.tokens.get(self.index + 1).map(|token| token.token_type) == Some(TokenType::Comma) {
+            let name = self.capture().text.to_string();
+            self.capture();
+
+            match self.expression() {
+                Ok(val) => bindings.push((name.clone(), val)),
+                Err(val) => return Err(val),
+            }
+            self.bound.push(name.to_lowercase());
+
+            if self.has(TokenType::Comma) {
+                self.capture();
+            } else {
+                return Err(self.error(String::from("Missing Body of Let")))
+            }
+        }
+
+        if bindings.is_empty() {
+            return Err(self.error(String::from("Missing Name in Let")))
+        }
+
+        let body: Box<dyn Evaluatable>;
+        match self.expression() {
+            Ok(val) => body = val,
+            Err(val) => return Err(val),
+        }
+        self.bound.truncate(bound);
+
+        if self.has(TokenType::CloseParenthesis) {
+            self.capture();
+            Ok(Box::new(Let { bindings, body }))
+        } else {
+            Err(self.error(String::from("Missing Closing Parenthesis")))
+        }
+    }
+
+    /// Parameters followed by the body of a function, such as lambda(x, y, x * y)
+    fn lambda(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
+        let mut parameters: Vec<String> = Vec::new();
+        self.capture();
+
+        // Like the names in a let, a parameter is always followed by a comma
+        while self.has(TokenType::Identifier) && self.tokens.get(self.index + 1).map(|token| token.token_type) == Some(TokenType::Comma) {
+            let parameter = self.tokens[self.index].text.to_string();
+            if parameters.iter().any(|other| other.eq_ignore_ascii_case(&parameter)) {
+                return Err(self.error(format!("Duplicate Parameter {parameter}")))
+            }
+            parameters.push(parameter);
+            self.capture();
+            self.capture();
+        }
+
+        let bound = self.bound.len();
+        self.bound.extend(parameters.iter().map(|parameter| parameter.to_lowercase()));
+        let body: Box<dyn Evaluatable>;
+        match self.expression() {
+            Ok(val) => body = val,
+            Err(val) => return Err(val),
+        }
+        self.bound.truncate(bound);
+
+        if self.has(TokenType::CloseParenthesis) {
+            self.capture();
+            Ok(Box::new(Lambda { parameters, body: Rc::from(body) }))
+        } else {
+            Err(self.error(String::from("Missing Closing Parenthesis")))
+        }
+    }
+
+    fn argument(&mut self, kind: ArgumentKind) -> Result<Box<dyn Evaluatable>, CellError> {
+        if kind == ArgumentKind::Range {
+            if let Some(range) = self.legacy_range() {
+                return Ok(range);
+            }
+        }
+        self.expression()
+    }
+
+    /// Range written as [x, y], [x, y], as accepted by functions taking ranges before the colon
+    /// syntax existed. Consumes no tokens unless they form such a range. Cells written in A1 form
+    /// and names of cells are separate arguments
+    fn legacy_range(&mut self) -> Option<Box<dyn Evaluatable>> {
+        let start = self.index;
+
+        if !self.has_bracket_address() {
+            return None;
+        }
+        let address = if self.has_sheet_name() { start + 2 } else { start };
+        if let Ok((left, left_absolute, _)) = self.cell_reference() {
+            if self.has(TokenType::Comma) {
+                self.capture();
+                // Both corners name the same sheet or neither does, as each could also be an
+                // argument of its own
+                if !self.has_bracket_address() {
+                    self.index = start;
+                    return None;
+                }
+                if let Ok((right, _, right_absolute)) = self.cell_reference() {
+                    if left.sheet == right.sheet && (self.has(TokenType::Comma) || self.has(TokenType::CloseParenthesis)) {
+                        let range = RangeValue(CellRange(left.range.0, right.range.1), left_absolute, right_absolute, self.span_from(address));
+                        return Some(self.on_sheet(left.sheet, Box::new(range), self.span_from(start)));
+                    }
+                }
+            }
+        }
+
+        self.index = start;
+        None
+    }
+
+    fn atom(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
         if self.has(TokenType::OpenParenthesis) {
             self.capture();
             let result = self.expression();
@@ -487,11 +676,16 @@ impl Parser<'_> {
                 self.capture();
                 Ok(result_unwrapped)
             } else {
-                Err(format!("Missing Closing Parenthesis at {}", self.index))
+                Err(self.error(String::from("Missing Closing Parenthesis")))
             }
         } else if self.has(TokenType::IntegerLiteral) {
-            let token = self.capture();
-            Ok(Box::new(Primitive::Integer(token.text.parse().unwrap())))
+            match self.tokens[self.index].text.parse() {
+                Ok(val) => {
+                    self.capture();
+                    Ok(Box::new(Primitive::Integer(val)))
+                }
+                Err(_) => Err(self.error(String::from("Integer literal out of range"))),
+            }
         } else if self.has(TokenType::FloatLiteral) {
             let token = self.capture();
             Ok(Box::new(Primitive::Float(token.text.to_string().parse().unwrap())))
@@ -504,48 +698,210 @@ impl Parser<'_> {
         } else if self.has(TokenType::True) {
             self.capture();
             Ok(Box::new(Primitive::Boolean(true)))
-        } else if self.has(TokenType::OpenBracket) {
-            match self.cell_address() {
-                Ok(val) => Ok(Box::new(CellValue(val.0, val.1))),
-                Err(val) => Err(val)
+        } else if self.has(TokenType::OpenBracket) || self.has(TokenType::A1Reference) || self.has_sheet_name() || self.named_range().is_some() {
+            let first = self.index;
+            let address = if self.has_sheet_name() { first + 2 } else { first };
+            let (left, left_absolute, left_end_absolute): (Reference, Absolute, Absolute);
+            match self.cell_reference() {
+                Ok(val) => (left, left_absolute, left_end_absolute) = val,
+                Err(val) => return Err(val)
             }
+
+            // A range between two named ranges spans from the start of the first to the end of
+            // the second
+            if self.has(TokenType::Colon) {
+                self.capture();
+                let (right, right_absolute): (Reference, Absolute);
+                match self.cell_reference() {
+                    Ok(val) => (right, _, right_absolute) = val,
+                    Err(val) => return Err(val)
+                }
+                match self.range_sheet(left.sheet, right.sheet) {
+                    Ok(sheet) => {
+                        let range = RangeValue(CellRange(left.range.0, right.range.1), left_absolute, right_absolute, self.span_from(address));
+                        Ok(self.on_sheet(sheet, Box::new(range), self.span_from(first)))
+                    }
+                    Err(val) => Err(val)
+                }
+            } else if left.range.0 == left.range.1 {
+                Ok(self.on_sheet(left.sheet, Box::new(CellValue(left.range.0.0, left.range.0.1, left_absolute, self.span_from(address))), self.span_from(first)))
+            } else {
+                Ok(self.on_sheet(left.sheet, Box::new(RangeValue(left.range, left_absolute, left_end_absolute, self.span_from(address))), self.span_from(first)))
+            }
+        } else if self.has(TokenType::Identifier) {
+            let token = self.capture();
+            Ok(Box::new(Variable(token.text.to_string())))
+        } else if self.has(TokenType::DeletedReference) {
+            self.capture();
+            Ok(Box::new(DeletedReference))
+        } else if self.has(TokenType::OpenCurlyBracket) {
+            self.array()
         } else {
             if self.index < self.tokens.len() {
-                Err(format!("Unexpected Token {} at index {}", self.tokens.get(self.index).unwrap().text, self.cur_token_index()))
+                Err(self.error(format!("Unexpected Token {}", self.tokens.get(self.index).unwrap().text)))
             } else {
-                Err(format!("Incomplete Input String (Likely a dropped primitive after an operator)"))
+                Err(self.error(String::from("Incomplete Input String (Likely a dropped primitive after an operator)")))
+            }
+        }
+    }
+
+    /// Array literal such as {1, 2; 3, 4}, with commas between the elements of a row and
+    /// semicolons between rows
+    fn array(&mut self) -> Result<Box<dyn Evaluatable>, CellError> {
+        let mut rows: Vec<Vec<Box<dyn Evaluatable>>> = vec![Vec::new()];
+        self.capture();
+
+        loop {
+            match self.expression() {
+                Ok(val) => rows.last_mut().unwrap().push(val),
+                Err(val) => return Err(val),
+            }
+
+            if self.has(TokenType::Comma) {
+                self.capture();
+            } else if self.has(TokenType::Semicolon) {
+                if rows.last().unwrap().len() != rows[0].len() {
+                    return Err(self.error(String::from("Rows of Array have Different Lengths")))
+                }
+                self.capture();
+                rows.push(Vec::new());
+            } else {
+                break;
+            }
+        }
+
+        if rows.last().unwrap().len() != rows[0].len() {
+            return Err(self.error(String::from("Rows of Array have Different Lengths")))
+        }
+
+        if self.has(TokenType::CloseCurlyBracket) {
+            self.capture();
+            Ok(Box::new(ArrayLiteral(rows)))
+        } else {
+            Err(self.error(String::from("Missing Closing Curly Bracket")))
+        }
+    }
+
+    /// Range of cells defined under the name at the current token, unless a let or lambda binds the
+    /// name or it is the name of a function being called
+    fn named_range(&self) -> Option<Reference> {
+        if !self.has(TokenType::Identifier) || self.tokens.get(self.index + 1).map(|token| token.token_type) == Some(TokenType::OpenParenthesis) {
+            return None;
+        }
+
+        let name = self.tokens[self.index].text.to_lowercase();
+        if self.bound.contains(&name) {
+            None
+        } else {
+            self.ranges.get(&name).copied()
+        }
+    }
+
+    /// Whether the current tokens start a cell address written as [x, y], optionally preceded by the
+    /// name of a sheet
+    fn has_bracket_address(&self) -> bool {
+        let offset = if self.has_sheet_name() { 2 } else { 0 };
+        self.tokens.get(self.index + offset).map(|token| token.token_type) == Some(TokenType::OpenBracket)
+    }
+
+    /// Whether the current token is the name of a sheet followed by '!', as in Inputs![0, 0]
+    fn has_sheet_name(&self) -> bool {
+        self.has(TokenType::Identifier) && self.tokens.get(self.index + 1).map(|token| token.token_type) == Some(TokenType::LogicalNot)
+    }
+
+    /// Cell address, optionally preceded by the name of the sheet it is on, or name of a cell or
+    /// range, as the range of cells it covers along with which of the columns and rows of its
+    /// corners are absolute. Names of cells and ranges are absolute
+    fn cell_reference(&mut self) -> Result<(Reference, Absolute, Absolute), CellError> {
+        let mut sheet: Option<SheetId> = None;
+        if self.has_sheet_name() {
+            let name = self.tokens[self.index].text;
+            match self.sheets.get(&name.to_lowercase()) {
+                Some(id) => sheet = Some(*id),
+                None => return Err(self.error(format!("Unknown Sheet {name}"))),
             }
+            self.capture();
+            self.capture();
+        } else if let Some(reference) = self.named_range() {
+            self.capture();
+            return Ok((reference, Absolute(true, true), Absolute(true, true)));
         }
+
+        match self.cell_address() {
+            Ok((adr, absolute)) => Ok((Reference { sheet, range: CellRange(adr, adr) }, absolute, absolute)),
+            Err(val) => Err(val),
+        }
+    }
+
+    /// Text from the given token up to the last token captured
+    fn span_from(&self, index: usize) -> Span {
+        (self.tokens[index].start, self.tokens[self.index - 1].end + 1)
     }
 
-    fn cell_address(&mut self) -> Result<CellAddress, String> {
+    /// Sheet of a range between two cell references, only one of which needs to name the sheet
+    fn range_sheet(&self, left: Option<SheetId>, right: Option<SheetId>) -> Result<Option<SheetId>, CellError> {
+        match (left, right) {
+            (Some(left), Some(right)) if left != right => Err(self.error(String::from("Range Spans Several Sheets"))),
+            _ => Ok(left.or(right)),
+        }
+    }
+
+    /// Reference that reads its cells from the given sheet, or from the sheet of the formula when
+    /// there is none
+    fn on_sheet(&self, sheet: Option<SheetId>, reference: Box<dyn Evaluatable>, span: Span) -> Box<dyn Evaluatable> {
+        match sheet {
+            Some(sheet) => {
+                let name = self.sheets.iter()
+                    .find(|(_, id)| **id == sheet)
+                    .map(|(name, _)| name.clone())
+                    .unwrap_or_default();
+                Box::new(SheetReference { sheet, name, reference, span })
+            }
+            None => reference,
+        }
+    }
+
+    /// Cell address written either as [x, y] or in A1 form, such as B7 or $B$7, along with which
+    /// of its column and row are absolute. Addresses written as [x, y] are relative
+    fn cell_address(&mut self) -> Result<(CellAddress, Absolute), CellError> {
         let left: i32;
         let right: i32;
-        if self.has(TokenType::OpenBracket) {
+        if self.has(TokenType::A1Reference) {
+            let token = self.capture();
+            Ok(CellAddress::from_a1(token.text).unwrap())
+        } else if self.has(TokenType::OpenBracket) {
             self.capture();
             if self.has(TokenType::IntegerLiteral) {
-                left = self.capture().text.parse().unwrap();
+                match self.tokens[self.index].text.parse() {
+                    Ok(val) => left = val,
+                    Err(_) => return Err(self.error(String::from("Cell Address out of range"))),
+                }
+                self.capture();
                 if self.has(TokenType::Comma) {
                     self.capture();
                     if self.has(TokenType::IntegerLiteral) {
-                        right = self.capture().text.parse().unwrap();
+                        match self.tokens[self.index].text.parse() {
+                            Ok(val) => right = val,
+                            Err(_) => return Err(self.error(String::from("Cell Address out of range"))),
+                        }
+                        self.capture();
                         if self.has(TokenType::CloseBracket) {
                             self.capture();
-                            Ok(CellAddress(left, right))
+                            Ok((CellAddress(left, right), Absolute::default()))
                         } else {
-                            Err(format!("Missing Close Bracket for Cell Address at index {}", self.cur_token_index()))
+                            Err(self.error(String::from("Missing Close Bracket for Cell Address")))
                         }
                     } else {
-                        Err(format!("Missing Second Integer in Cell Address at index {}", self.cur_token_index()))
+                        Err(self.error(String::from("Missing Second Integer in Cell Address")))
                     }
                 } else {
-                    Err(format!("Missing Comma in Cell Address at index {}", self.cur_token_index()))
+                    Err(self.error(String::from("Missing Comma in Cell Address")))
                 }
             } else {
-                Err(format!("Missing First Integer in Cell Address at index {}", self.cur_token_index()))
+                Err(self.error(String::from("Missing First Integer in Cell Address")))
             }
         } else {
-            Err(format!("Missing open bracket for cell address at index {}", self.cur_token_index()))
+            Err(self.error(String::from("Missing open bracket for cell address")))
         }
     }
 }
@@ -1,4 +1,4 @@
-use crate::{model::{Primitive, Evaluatable, CellAddress}, environment::Environment, grid::Grid, lexer::{self, Token}, parser};
+use crate::{model::{Primitive, Evaluatable, CellAddress}, environment::{Environment, NameTable}, functions::FunctionRegistry, grid::{self, Grid}, lexer::{self, Token}, parser};
 
 fn _testing() {
     fn _print_expression_title(expression: &str, title: &str, grid: &Grid) {
@@ -31,7 +31,7 @@ fn _testing() {
         }
         
         let parsed_expression: Box<dyn Evaluatable>;
-        match parser::parse(tokens) {
+        match parser::parse(tokens, &FunctionRegistry::new(), &parser::NamedRanges::new(), &parser::SheetNames::new()) {
             Ok(result) => {
                 println!("PARSED VALUE: {:?}", result);
                 parsed_expression = result;
@@ -42,7 +42,7 @@ fn _testing() {
             }
         }
 
-        match parsed_expression.evaluate(&Environment::new(grid)) {
+        match parsed_expression.evaluate(&Environment::new(grid, &FunctionRegistry::new(), &NameTable::new())) {
             Ok(result) => println!("EVALUATION: {}", result.to_string()),
             Err(err) => println!("ERROR: {err}\n"),
         }
@@ -50,6 +50,20 @@ fn _testing() {
         println!();
     }
 
+    fn print_retarget(expression: &str, offset: (i32, i32)) {
+        println!("INPUT: {expression}");
+
+        let mut parsed_expression = match grid::parse_from_string(expression, &FunctionRegistry::new(), &parser::NamedRanges::new(), &parser::SheetNames::new()) {
+            Ok(result) => result,
+            Err(err) => {
+                println!("PARSING ERROR: {err}\n");
+                return;
+            }
+        };
+        parsed_expression.retarget(offset, &mut Vec::new());
+        println!("RETARGETED BY {offset:?}: {}\n", parsed_expression.to_string());
+    }
+
     fn header(title: &str) {
         println!("{}", title.to_uppercase());
         println!("==============================================");
@@ -60,7 +74,7 @@ fn _testing() {
     let mut grid = Grid::new();
     for i in 1..=10 {
         for j in 1..=10 {
-            grid.set_cell(&CellAddress(i, j), Ok(Primitive::Integer(i * j)));
+            grid.set_cell(&CellAddress(i, j), Ok(Primitive::Integer((i * j) as i64)));
         }
     }
 
@@ -72,6 +86,7 @@ fn _testing() {
     print_expression("true", &grid);
     print_expression("false", &grid);
     print_expression("[5, 5]", &grid);
+    print_expression("F6", &grid);
 
     header("basic expressions");
     print_expression("2 + 2", &grid);
@@ -111,6 +126,8 @@ fn _testing() {
     print_expression("-0.00001 != 0.00001", &grid);
     print_expression("\"this is a string\" != \"this is a different string\"", &grid);
     print_expression("true != false", &grid);
+    print_expression("2.5 >= 1.0", &grid);
+    print_expression("1.0 >= 2.5", &grid);
 
     print_expression("\"this is string\" != 5", &grid);
 
@@ -119,6 +136,53 @@ fn _testing() {
     print_expression("min([1,1],[5,5])", &grid);
     print_expression("sum([1,1],[5,5])", &grid);
     print_expression("mean([1,1], [5,5])", &grid);
+    print_expression("max(1, 2, 3)", &grid);
+    print_expression("sum(B2:F6)", &grid);
+    print_expression("sum($B$2:F$6) + $F6", &grid);
+    print_expression("sum([7, 7]:[7, 9])", &grid);
+    print_expression("sum([1,1]:[5,5])", &grid);
+    print_expression("SUM([1,1]:[5,5])", &grid);
+
+    header("ranges");
+    print_expression("[1,1]:[2,2]", &grid);
+    print_expression("[1,1]:[1,5] * 2", &grid);
+    print_expression("[1,1]:[1,5] + [2,1]:[2,5]", &grid);
+    print_expression("sum([1,1]:[1,5] * [2,1]:[2,5])", &grid);
+
+    header("retargeting");
+    print_retarget("$A1 + A$1 + $A$1 + A1", (1, 1));
+    print_retarget("sum($A1:B$2)", (2, 3));
+    print_retarget("A2 + $A$1", (0, -2));
+
+    header("array literals");
+    print_expression("{1, 2, 3}", &grid);
+    print_expression("{1, 2; 3, 4} * 2", &grid);
+    print_expression("sum({1.5, 2.5; [1,1], 4})", &grid);
+    print_expression("{1, 2; 3}", &grid);
+
+    header("conditionals");
+    print_expression("if([1,1] > 0, \"positive\", \"negative\")", &grid);
+    print_expression("if(false, 1 / 0, 2)", &grid);
+    print_expression("iferror(1 / 0, 0)", &grid);
+    print_expression("false && 1 / 0 > 1", &grid);
+    print_expression("true || 1 / 0 > 1", &grid);
+
+    header("let");
+    print_expression("let(x, [1,1] * 2, x + x)", &grid);
+    print_expression("let(x, 2, y, x ** 3, LET(x, 10, x + y))", &grid);
+    print_expression("let(x, 2, z)", &grid);
+
+    header("lambdas");
+    print_expression("let(double, lambda(x, x * 2), double([1,1]))", &grid);
+    print_expression("let(k, 3, scale, lambda(x, x * k), scale(2))", &grid);
+    print_expression("let(f, lambda(x, y, x + y), f(1))", &grid);
+
+    header("blank cells");
+    print_expression("[0, 0]", &grid);
+    print_expression("[0, 0] + 5", &grid);
+    print_expression("[0, 0] == \"\"", &grid);
+    print_expression("sum([0, 0], [2, 2])", &grid);
+    print_expression("mean([0, 0], [0, 5])", &grid);
 
     header("precedence");
     print_expression("4 | 7 ^ 8", &grid);
@@ -144,6 +208,9 @@ fn _testing() {
     print_expression("4 < (7 == 7) > 4", &grid);
     print_expression("1 << (7 < 16)", &grid);
     print_expression("(1 << 5) + 2", &grid);
+    print_expression("1 << 62", &grid);
+    print_expression("1 << 63", &grid);
+    print_expression("-1 << 63", &grid);
     print_expression("(4445 >> 4) - 2", &grid);
     print_expression("(27 - 81) / 5,800,074", &grid);
     print_expression("(2 + 5) * 9", &grid);
@@ -166,6 +233,15 @@ fn _testing() {
     print_expression("17.99999 - )", &grid);
     print_expression("this is not a (spreadterm) string, it is not in quotes", &grid);
     print_expression("[5, 4", &grid);
+    print_expression("[99999999999, 0]", &grid);
     print_expression("max([1, 1],)", &grid);
+    print_expression("max([1, 1]:[1, 5], [2, 2]:[2, 5])", &grid);
+    print_expression("median([1, 1]:[1, 5])", &grid);
+    print_expression("#REF! + 1", &grid);
+    print_expression("$B + 1", &grid);
+    print_expression("sum([0, 0]:#REF!)", &grid);
+    print_expression("Inputs![0, 0] + 1", &grid);
+    print_expression("1 2", &grid);
+    print_expression("é + 1", &grid);
     print_expression("(5 - 5", &grid)
 }
@@ -0,0 +1,1090 @@
+use crate::{model::{Absolute, Evaluatable, CellAddress, CellRange, Primitive, Reference, SheetId, Span}, error::{CellError, ErrorCell}, functions::{ArgumentKind, ArgumentValue, FunctionRegistry}, grid::{parse_from_string, Grid, Grids}, lexer::{lex, Token, TokenType}, parser::{parse_reference, NamedRanges, SheetNames, SPECIAL_FORMS}, environment::{Environment, NameTable}};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Address of a cell on a particular sheet of a workbook
+pub type SheetAddress = (SheetId, CellAddress);
+
+/// Block of cells that an array result is written into, starting at the formula cell
+#[derive(Clone, Copy, PartialEq)]
+struct Spill {
+    area: CellRange,
+    /// Whether the array could not be written because another cell in the area has contents
+    blocked: bool,
+}
+
+/// Formula cells reading the cells of a sheet
+#[derive(Default)]
+struct Readers {
+    /// Formula cells reading each single cell
+    cells: HashMap<CellAddress, HashSet<SheetAddress>>,
+    /// Formula cells reading each range of more than one cell
+    ranges: HashMap<CellRange, HashSet<SheetAddress>>,
+}
+
+impl Readers {
+    fn insert(&mut self, range: CellRange, reader: SheetAddress) {
+        let readers = match range.0 == range.1 {
+            true => self.cells.entry(range.0).or_default(),
+            false => self.ranges.entry(range).or_default(),
+        };
+        readers.insert(reader);
+    }
+
+    fn remove(&mut self, range: CellRange, reader: SheetAddress) {
+        if range.0 == range.1 {
+            if let Some(readers) = self.cells.get_mut(&range.0) {
+                readers.remove(&reader);
+                if readers.is_empty() {
+                    self.cells.remove(&range.0);
+                }
+            }
+        } else if let Some(readers) = self.ranges.get_mut(&range) {
+            readers.remove(&reader);
+            if readers.is_empty() {
+                self.ranges.remove(&range);
+            }
+        }
+    }
+
+    /// Formula cells reading any cell of the given area
+    fn reading(&self, area: CellRange) -> HashSet<SheetAddress> {
+        let mut readers: HashSet<SheetAddress> = HashSet::new();
+        if area.size() <= self.cells.len() as i64 {
+            for adr in area.cells() {
+                readers.extend(self.cells.get(&adr).into_iter().flatten());
+            }
+        } else {
+            readers.extend(self.cells.iter().filter(|(adr, _)| area.contains(adr)).flat_map(|(_, cells)| cells));
+        }
+        readers.extend(self.ranges.iter().filter(|(range, _)| range.intersects(&area)).flat_map(|(_, cells)| cells));
+        readers
+    }
+}
+
+/// Times cells are recalculated in one update before the ones still moving get an error
+const MAX_SPILL_PASSES: usize = 8;
+
+#[derive(Clone, Copy)]
+enum Axis {
+    Row,
+    Column,
+}
+
+/// Rows or columns inserted into or deleted from a sheet, which moves the cells after them
+#[derive(Clone, Copy)]
+struct Shift {
+    sheet: SheetId,
+    axis: Axis,
+    /// First row or column inserted or deleted
+    at: i32,
+    /// Number of rows or columns inserted, or deleted when negative
+    count: i32,
+}
+
+impl Shift {
+    fn coordinate(&self, adr: CellAddress) -> i32 {
+        match self.axis {
+            Axis::Row => adr.1,
+            Axis::Column => adr.0,
+        }
+    }
+
+    fn with_coordinate(&self, adr: CellAddress, coordinate: i32) -> CellAddress {
+        match self.axis {
+            Axis::Row => CellAddress(adr.0, coordinate),
+            Axis::Column => CellAddress(coordinate, adr.1),
+        }
+    }
+
+    /// Where the given cell ends up, or None if it is deleted or pushed off the sheet
+    fn address(&self, adr: CellAddress) -> Option<CellAddress> {
+        let coordinate = self.coordinate(adr);
+        if coordinate < self.at {
+            Some(adr)
+        } else if (coordinate as i64) < self.at as i64 - self.count as i64 {
+            None
+        } else {
+            Some(self.with_coordinate(adr, coordinate.checked_add(self.count)?))
+        }
+    }
+
+    /// Cells the given range covers once the cells are moved, or None if all of them are deleted
+    fn range(&self, range: CellRange) -> Option<CellRange> {
+        if self.count > 0 || self.coordinate(range.0) > self.coordinate(range.1) {
+            return Some(CellRange(self.address(range.0)?, self.address(range.1)?));
+        }
+
+        // Corners inside the deleted rows or columns move to the edge of what is left of the range
+        let start = self.address(range.0).unwrap_or_else(|| self.with_coordinate(range.0, self.at));
+        let end = self.address(range.1).unwrap_or_else(|| self.with_coordinate(range.1, self.at.saturating_sub(1)));
+        if self.coordinate(start) <= self.coordinate(end) {
+            Some(CellRange(start, end))
+        } else {
+            None
+        }
+    }
+}
+
+/// Sheet of a workbook
+struct Sheet {
+    id: SheetId,
+    name: String,
+}
+
+/// Sheets of cells along with the names and functions their formulas share
+pub struct Workbook {
+    /// Sheets in the order they are shown
+    sheets: Vec<Sheet>,
+    /// Id of each sheet, keyed by lowercase name
+    sheet_ids: SheetNames,
+    /// Id given to the next sheet added, so that ids of removed sheets are never reused
+    next_sheet_id: SheetId,
+    /// Values of the cells of each sheet
+    grids: Grids,
+    map: HashMap<SheetAddress, String>,
+    /// Parsed contents of each non-empty cell, kept until the text of the cell changes
+    expressions: HashMap<SheetAddress, Result<Box<dyn Evaluatable>, CellError>>,
+    /// Ranges read by each formula cell, i.e. its precedents, along with the sheets they are on
+    references: HashMap<SheetAddress, Vec<(SheetId, CellRange)>>,
+    /// Formula cells reading the cells of each sheet
+    readers: HashMap<SheetId, Readers>,
+    /// Functions that formulas can call
+    functions: FunctionRegistry,
+    /// Spill of each formula cell whose value is an array
+    spills: HashMap<SheetAddress, Spill>,
+    /// Formulas defined under a name, such as lambdas, keyed by lowercase name
+    names: NameTable,
+    /// Cells and ranges defined under a name, which formulas use in place of a cell address
+    ranges: NamedRanges,
+    /// Text of each defined name, keyed by lowercase name
+    name_text: HashMap<String, String>,
+}
+
+impl Default for Workbook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Workbook {
+    /// Workbook holding a single empty sheet named Sheet1
+    pub fn new() -> Workbook {
+        let mut workbook = Workbook {
+            sheets: Vec::new(),
+            sheet_ids: HashMap::new(),
+            next_sheet_id: 0,
+            grids: HashMap::new(),
+            map: HashMap::new(),
+            expressions: HashMap::new(),
+            references: HashMap::new(),
+            readers: HashMap::new(),
+            functions: FunctionRegistry::new(),
+            spills: HashMap::new(),
+            names: HashMap::new(),
+            ranges: HashMap::new(),
+            name_text: HashMap::new(),
+        };
+        workbook.add_sheet("Sheet1").unwrap();
+        workbook
+    }
+
+    /// Ids and names of the sheets, in the order they are shown
+    pub fn get_sheets(&self) -> Vec<(SheetId, &String)> {
+        self.sheets.iter().map(|sheet| (sheet.id, &sheet.name)).collect()
+    }
+
+    /// Id of the sheet with the given name, regardless of case
+    pub fn get_sheet_id(&self, name: &str) -> Option<SheetId> {
+        self.sheet_ids.get(&name.to_lowercase()).copied()
+    }
+
+    pub fn get_sheet_name(&self, sheet: SheetId) -> Option<&String> {
+        self.sheets.iter().find(|other| other.id == sheet).map(|other| &other.name)
+    }
+
+    /// Position of the sheet among the sheets
+    fn sheet_index(&self, sheet: SheetId) -> Result<usize, CellError> {
+        match self.sheets.iter().position(|other| other.id == sheet) {
+            Some(index) => Ok(index),
+            None => Err(CellError::Ref(String::from("Reference to a sheet that does not exist"))),
+        }
+    }
+
+    /// Adds an empty sheet after the others, returning its id
+    pub fn add_sheet(&mut self, name: &str) -> Result<SheetId, CellError> {
+        if !is_identifier(name) {
+            return Err(CellError::Name(format!("Invalid sheet name {name}")));
+        } else if self.sheet_ids.contains_key(&name.to_lowercase()) {
+            return Err(CellError::Name(format!("Sheet {name} already exists")));
+        }
+
+        let id = self.next_sheet_id;
+        self.next_sheet_id += 1;
+        self.sheets.push(Sheet { id, name: name.to_string() });
+        self.sheet_ids.insert(name.to_lowercase(), id);
+        self.grids.insert(id, Grid::for_sheet(name));
+        self.reparse_all();
+        Ok(id)
+    }
+
+    /// Renames a sheet along with the references to it
+    pub fn rename_sheet(&mut self, sheet: SheetId, name: &str) -> Result<Vec<SheetAddress>, CellError> {
+        let index = self.sheet_index(sheet)?;
+        if !is_identifier(name) {
+            return Err(CellError::Name(format!("Invalid sheet name {name}")));
+        } else if self.get_sheet_id(name).is_some_and(|other| other != sheet) {
+            return Err(CellError::Name(format!("Sheet {name} already exists")));
+        }
+
+        let old_name = std::mem::replace(&mut self.sheets[index].name, name.to_string());
+        self.rewrite_formulas(|_, formula| rename_sheet_references(formula, &old_name, name));
+        self.sheet_ids.remove(&old_name.to_lowercase());
+        self.sheet_ids.insert(name.to_lowercase(), sheet);
+        self.grid_mut(sheet).set_name(name);
+        Ok(self.reparse_all())
+    }
+
+    /// Moves a sheet to the given position, or after the last one
+    pub fn move_sheet(&mut self, sheet: SheetId, index: usize) -> Result<(), CellError> {
+        let current = self.sheet_index(sheet)?;
+        let moved = self.sheets.remove(current);
+        self.sheets.insert(index.min(self.sheets.len()), moved);
+        Ok(())
+    }
+
+    /// Deletes a sheet, turning the references to it into #REF!
+    pub fn remove_sheet(&mut self, sheet: SheetId) -> Result<Vec<SheetAddress>, CellError> {
+        let index = self.sheet_index(sheet)?;
+        if self.sheets.len() == 1 {
+            return Err(CellError::Ref(String::from("The only sheet of a workbook cannot be deleted")));
+        }
+
+        let removed = self.sheets.remove(index);
+        self.rewrite_formulas(|_, formula| delete_sheet_references(formula, &removed.name));
+        self.sheet_ids.remove(&removed.name.to_lowercase());
+        self.grids.remove(&sheet);
+        self.map.retain(|(other, _), _| *other != sheet);
+        self.expressions.retain(|(other, _), _| *other != sheet);
+        let removed_cells: Vec<SheetAddress> = self.references.keys().filter(|(other, _)| *other == sheet).copied().collect();
+        for cell in removed_cells {
+            self.set_references(cell, None);
+        }
+        self.readers.remove(&sheet);
+        self.spills.retain(|(other, _), _| *other != sheet);
+        Ok(self.reparse_all())
+    }
+
+    pub fn get_cell_text(&self, sheet: SheetId, adr: CellAddress) -> Option<&String> {
+        self.map.get(&(sheet, adr))
+    }
+
+    /// Sets the text of a cell, returning the recalculated cells in evaluation order
+    pub fn set_cell_text(&mut self, sheet: SheetId, adr: CellAddress, str: String) -> Vec<SheetAddress> {
+        self.parse_cell((sheet, adr), &str);
+        self.map.insert((sheet, adr), str);
+
+        let dirty = self.dirty_cells((sheet, adr));
+        self.update_cells(&dirty)
+    }
+
+    /// Adds a function that formulas can call, as in [`FunctionRegistry::register`]
+    pub fn register_function<F>(&mut self, name: &str, parameters: Vec<ArgumentKind>, variadic: bool, implementation: F) -> Result<Vec<SheetAddress>, CellError>
+    where
+        F: Fn(&[ArgumentValue]) -> Result<Primitive, CellError> + 'static,
+    {
+        self.functions.register(name, parameters, variadic, implementation)?;
+        Ok(self.reparse_all())
+    }
+
+    /// Inserts empty rows before the given row of a sheet
+    pub fn insert_rows(&mut self, sheet: SheetId, at: i32, count: i32) -> Vec<SheetAddress> {
+        self.shift(Shift { sheet, axis: Axis::Row, at, count: count.max(0) })
+    }
+
+    /// Deletes rows of a sheet starting at the given row
+    pub fn delete_rows(&mut self, sheet: SheetId, at: i32, count: i32) -> Vec<SheetAddress> {
+        self.shift(Shift { sheet, axis: Axis::Row, at, count: -count.max(0) })
+    }
+
+    /// Inserts empty columns before the given column, as [`Workbook::insert_rows`] does for rows
+    pub fn insert_columns(&mut self, sheet: SheetId, at: i32, count: i32) -> Vec<SheetAddress> {
+        self.shift(Shift { sheet, axis: Axis::Column, at, count: count.max(0) })
+    }
+
+    /// Deletes columns starting at the given column, as [`Workbook::delete_rows`] does for rows
+    pub fn delete_columns(&mut self, sheet: SheetId, at: i32, count: i32) -> Vec<SheetAddress> {
+        self.shift(Shift { sheet, axis: Axis::Column, at, count: -count.max(0) })
+    }
+
+    /// Moves the cells of the shifted sheet along with the references to them
+    fn shift(&mut self, shift: Shift) -> Vec<SheetAddress> {
+        let sheet_ids = self.sheet_ids.clone();
+        self.rewrite_formulas(|cell, formula| shift_formula(formula, cell.map(|cell| cell.0), &shift, &sheet_ids));
+        self.map = std::mem::take(&mut self.map).into_iter()
+            .filter_map(|((sheet, adr), text)| {
+                if sheet == shift.sheet {
+                    Some(((sheet, shift.address(adr)?), text))
+                } else {
+                    Some(((sheet, adr), text))
+                }
+            })
+            .collect();
+        self.reload()
+    }
+
+    /// Moves a block of cells, as when cut and pasted, along with the references to them
+    pub fn move_cells(&mut self, sheet: SheetId, range: CellRange, to_sheet: SheetId, to: CellAddress) -> Result<Vec<SheetAddress>, CellError> {
+        let offset = (to.0 - range.0.0, to.1 - range.0.1);
+        if range.1.0.checked_add(offset.0).is_none() || range.1.1.checked_add(offset.1).is_none() {
+            return Err(CellError::Ref(String::from("Cells cannot be moved past the edge of the grid")));
+        }
+        let destination = |adr: CellAddress| CellAddress(adr.0 + offset.0, adr.1 + offset.1);
+        let moved = Move { sheet, range, to_sheet, offset };
+        let sheet_ids = self.sheet_ids.clone();
+        let sheet_names: HashMap<SheetId, String> = self.sheets.iter().map(|other| (other.id, other.name.clone())).collect();
+        self.rewrite_formulas(|cell, formula| move_formula(formula, cell, &moved, &sheet_ids, &sheet_names));
+
+        let texts: Vec<(CellAddress, String)> = self.map.iter()
+            .filter(|((other, adr), _)| *other == sheet && range.contains(adr))
+            .map(|((_, adr), text)| (*adr, text.clone()))
+            .collect();
+        let target = CellRange(to, destination(range.1));
+        self.map.retain(|(other, adr), _| !((*other == sheet && range.contains(adr)) || (*other == to_sheet && target.contains(adr))));
+        self.map.extend(texts.into_iter().map(|(adr, text)| ((to_sheet, destination(adr)), text)));
+        Ok(self.reload())
+    }
+
+    /// Formula as it reads once copied from one cell to another
+    pub fn retarget_formula(&self, formula: &str, from: CellAddress, to: CellAddress) -> String {
+        let Ok(mut expression) = parse_from_string(formula, &self.functions, &self.ranges, &self.sheet_ids) else {
+            return formula.to_string();
+        };
+
+        let mut edits: Vec<(Span, String)> = Vec::new();
+        expression.retarget((to.0 - from.0, to.1 - from.1), &mut edits);
+        edits.sort_by_key(|(span, _)| span.0);
+        replace_spans(formula, edits.into_iter().map(|((start, end), text)| (start, end, text)).collect())
+    }
+
+    /// Parses and recalculates every cell from its text
+    fn reload(&mut self) -> Vec<SheetAddress> {
+        for grid in self.grids.values_mut() {
+            grid.clear();
+        }
+        self.expressions.clear();
+        self.references.clear();
+        self.readers.clear();
+        self.spills.clear();
+        self.reparse_all()
+    }
+
+    /// Replaces the text of every formula, the cell being None for the formula of a name
+    fn rewrite_formulas<F: Fn(Option<SheetAddress>, &str) -> String>(&mut self, rewrite: F) {
+        for (cell, text) in self.map.iter_mut() {
+            if let Some(formula) = text.strip_prefix('=') {
+                *text = format!("={}", rewrite(Some(*cell), formula));
+            }
+        }
+        for formula in self.name_text.values_mut() {
+            *formula = rewrite(None, formula);
+        }
+    }
+
+    /// Text of the formula defined under the given name, regardless of case
+    pub fn get_name(&self, name: &str) -> Option<&String> {
+        self.name_text.get(&name.to_lowercase())
+    }
+
+    /// Defines a name for the given formula, qualifying its references with the given sheet
+    pub fn set_name(&mut self, sheet: SheetId, name: &str, formula: &str) -> Result<Vec<SheetAddress>, CellError> {
+        if !is_identifier(name) {
+            return Err(CellError::Name(format!("Invalid name {name}")));
+        } else if SPECIAL_FORMS.contains(&name.to_lowercase().as_str()) || self.functions.get(name).is_some() {
+            return Err(CellError::Name(format!("Name {name} is already a function")));
+        }
+
+        parse_from_string(formula, &self.functions, &self.ranges, &self.sheet_ids)?;
+        let sheet_name = &self.sheets[self.sheet_index(sheet)?].name;
+        self.name_text.insert(name.to_lowercase(), qualify_formula(formula, sheet_name));
+        Ok(self.reparse_all())
+    }
+
+    /// Removes the formula defined under the given name
+    pub fn remove_name(&mut self, name: &str) -> Vec<SheetAddress> {
+        self.name_text.remove(&name.to_lowercase());
+        self.reparse_all()
+    }
+
+    /// Names defined in the workbook along with their formulas, ordered by name
+    pub fn get_names(&self) -> Vec<(&String, &String)> {
+        let mut names: Vec<(&String, &String)> = self.name_text.iter().collect();
+        names.sort();
+        names
+    }
+
+    /// Parses every name and cell again, as needed when a change affects how formulas parse
+    fn reparse_all(&mut self) -> Vec<SheetAddress> {
+        self.ranges = self.name_text.iter()
+            .filter_map(|(name, formula)| Some((name.clone(), parse_reference(lex(formula).ok()?, &self.functions, &self.sheet_ids)?)))
+            .collect();
+        self.names = self.name_text.iter()
+            .filter_map(|(name, formula)| Some((name.clone(), parse_from_string(formula, &self.functions, &self.ranges, &self.sheet_ids).ok()?)))
+            .collect();
+
+        let cells: Vec<(SheetAddress, String)> = self.map.iter().map(|(cell, text)| (*cell, text.clone())).collect();
+        for (cell, text) in &cells {
+            self.parse_cell(*cell, text);
+        }
+
+        let dirty: HashSet<SheetAddress> = self.map.keys().copied().collect();
+        self.update_cells(&dirty)
+    }
+
+    /// Ranges read by an expression, including those read by the names it uses
+    fn expression_references(&self, sheet: SheetId, expression: &dyn Evaluatable) -> Vec<(SheetId, CellRange)> {
+        let mut references = expression.references();
+        let mut visited: HashSet<String> = HashSet::new();
+        let mut names: Vec<String> = expression.names();
+
+        while let Some(name) = names.pop() {
+            let name = name.to_lowercase();
+            if let Some(named) = self.names.get(&name) {
+                if visited.insert(name) {
+                    references.append(&mut named.references());
+                    names.append(&mut named.names());
+                }
+            }
+        }
+
+        references.into_iter()
+            .map(|reference: Reference| (reference.sheet.unwrap_or(sheet), reference.range))
+            .collect()
+    }
+
+    /// Caches the parsed text of a cell along with the ranges it references
+    fn parse_cell(&mut self, cell: SheetAddress, text: &str) {
+        match parse_cell_text(text, &self.functions, &self.ranges, &self.sheet_ids) {
+            Some(expression) => {
+                let references = match &expression {
+                    Ok(val) => Some(self.expression_references(cell.0, val.as_ref())),
+                    Err(_) => None,
+                };
+                self.set_references(cell, references);
+                self.expressions.insert(cell, expression);
+            }
+            None => {
+                self.set_references(cell, None);
+                self.expressions.remove(&cell);
+            }
+        }
+    }
+
+    /// Replaces the ranges a cell references, keeping the readers of every sheet up to date
+    fn set_references(&mut self, cell: SheetAddress, references: Option<Vec<(SheetId, CellRange)>>) {
+        for (sheet, range) in self.references.remove(&cell).into_iter().flatten() {
+            if let Some(readers) = self.readers.get_mut(&sheet) {
+                readers.remove(range, cell);
+            }
+        }
+        if let Some(references) = references {
+            for (sheet, range) in &references {
+                self.readers.entry(*sheet).or_default().insert(*range, cell);
+            }
+            self.references.insert(cell, references);
+        }
+    }
+
+    /// The given cell and all cells that depend on it, including spills it may block
+    fn dirty_cells(&self, cell: SheetAddress) -> HashSet<SheetAddress> {
+        let mut dirty: HashSet<SheetAddress> = HashSet::from([cell]);
+        let mut queue: VecDeque<SheetAddress> = VecDeque::from([cell]);
+
+        for (anchor, spill) in &self.spills {
+            if anchor.0 == cell.0 && spill.area.contains(&cell.1) && dirty.insert(*anchor) {
+                queue.push_back(*anchor);
+            }
+        }
+
+        while let Some(cell) = queue.pop_front() {
+            for dependent in self.dependents(&cell) {
+                if dirty.insert(dependent) {
+                    queue.push_back(dependent);
+                }
+            }
+        }
+
+        dirty
+    }
+
+    /// Re-evaluates the dirty cells in dependency order, giving circular ones an error
+    fn update_cells(&mut self, dirty: &HashSet<SheetAddress>) -> Vec<SheetAddress> {
+        let mut updated: Vec<SheetAddress> = Vec::new();
+        let mut dirty: HashSet<SheetAddress> = dirty.clone();
+
+        for _ in 0..MAX_SPILL_PASSES {
+            let mut moved: Vec<SheetAddress> = Vec::new();
+
+            for component in self.evaluation_order(&dirty) {
+                updated.extend(&component);
+                if component.len() > 1 || self.precedents(&component[0]).contains(&component[0]) {
+                    for cell in &component {
+                        let path = self.cycle_path(cell, &component).into_iter().map(|cell| self.error_cell(cell)).collect();
+                        self.grid_mut(cell.0).set_cell(&cell.1, Err(CellError::Circular(path)));
+                        let old_spill = self.clear_spill(*cell);
+                        updated.extend(spill_cells(cell.0, old_spill));
+                        moved.extend(spill_cells(cell.0, old_spill));
+                    }
+                } else {
+                    let cell = component[0];
+                    let old_spill = self.clear_spill(cell);
+                    self.evaluate_cell(cell);
+                    let new_spill = self.spills.get(&cell).copied();
+                    updated.extend(spill_cells(cell.0, old_spill));
+                    updated.extend(spill_cells(cell.0, new_spill));
+                    if old_spill != new_spill {
+                        moved.extend(spill_cells(cell.0, old_spill));
+                        moved.extend(spill_cells(cell.0, new_spill));
+                    }
+                }
+            }
+
+            dirty = moved.into_iter().flat_map(|cell| self.dirty_cells(cell)).collect();
+            if dirty.is_empty() {
+                break;
+            }
+        }
+
+        // Cells still dirty once the passes run out may hold stale values
+        for cell in dirty {
+            if self.expressions.contains_key(&cell) {
+                let old_spill = self.clear_spill(cell);
+                updated.extend(spill_cells(cell.0, old_spill));
+                self.grid_mut(cell.0).set_cell(&cell.1, Err(CellError::UnsettledSpill(MAX_SPILL_PASSES)));
+                updated.push(cell);
+            }
+        }
+
+        let mut seen: HashSet<SheetAddress> = HashSet::new();
+        updated.retain(|cell| seen.insert(*cell));
+        updated
+    }
+
+    fn grid_mut(&mut self, sheet: SheetId) -> &mut Grid {
+        self.grids.entry(sheet).or_insert_with(Grid::new)
+    }
+
+    /// Removes the values the given cell spilled into other cells, returning its spill
+    fn clear_spill(&mut self, cell: SheetAddress) -> Option<Spill> {
+        let spill = self.spills.remove(&cell)?;
+        if !spill.blocked {
+            for adr in area_cells(&spill.area) {
+                if adr != cell.1 && !self.expressions.contains_key(&(cell.0, adr)) {
+                    self.grid_mut(cell.0).remove_cell(&adr);
+                }
+            }
+        }
+        Some(spill)
+    }
+
+    /// Writes an array result into the given formula cell and the cells to its right and below
+    fn spill(&mut self, cell: SheetAddress, rows: Vec<Vec<Primitive>>) -> Result<Primitive, CellError> {
+        let (sheet, adr) = cell;
+        let height = rows.len() as i64;
+        let width = rows.first().map_or(0, |row| row.len()) as i64;
+        if width == 0 {
+            return Err(CellError::Value(String::from("Empty array")));
+        }
+
+        let (Ok(right), Ok(bottom)) = (i32::try_from(adr.0 as i64 + width - 1), i32::try_from(adr.1 as i64 + height - 1)) else {
+            return Err(CellError::Ref(String::from("Array does not fit in the sheet")));
+        };
+        let area = CellRange(adr, CellAddress(right, bottom));
+
+        // A formula reading its own spill would spill something else every time it is recalculated
+        let read_cell = self.references.get(&cell).into_iter().flatten()
+            .find(|(range_sheet, range)| *range_sheet == sheet && range.intersects(&area))
+            .map(|(_, range)| CellAddress(range.0.0.max(area.0.0), range.0.1.max(area.0.1)));
+        if let Some(other) = read_cell {
+            return Err(CellError::Circular(vec![self.error_cell(cell), self.error_cell((sheet, other)), self.error_cell(cell)]));
+        }
+
+        let blocking_cell = area_cells(&area).into_iter().find(|other| {
+            *other != adr && (self.expressions.contains_key(&(sheet, *other)) || self.spilled_into(&(sheet, *other)))
+        });
+        self.spills.insert(cell, Spill { area, blocked: blocking_cell.is_some() });
+        if let Some(other) = blocking_cell {
+            return Err(CellError::Spill(other));
+        }
+
+        // The formula cell itself holds the top left element
+        let mut vals = rows.into_iter().flatten();
+        let top_left = vals.next().unwrap();
+        let grid = self.grid_mut(sheet);
+        for (other, val) in area_cells(&area).into_iter().skip(1).zip(vals) {
+            grid.set_cell(&other, Ok(val));
+        }
+        Ok(top_left)
+    }
+
+    /// Cell as named in errors, along with its sheet
+    fn error_cell(&self, (sheet, adr): SheetAddress) -> ErrorCell {
+        ErrorCell { sheet: self.get_sheet_name(sheet).cloned(), adr }
+    }
+
+    /// Whether the given cell holds part of the array result of another cell
+    fn spilled_into(&self, cell: &SheetAddress) -> bool {
+        self.spills.iter().any(|(anchor, spill)| anchor != cell && anchor.0 == cell.0 && !spill.blocked && spill.area.contains(&cell.1))
+    }
+
+    /// Strongly connected components of the dirty cells (Tarjan), each after those it references.
+    /// A cycle is either entirely dirty or entirely clean, as its members all depend on each other
+    fn evaluation_order(&self, dirty: &HashSet<SheetAddress>) -> Vec<Vec<SheetAddress>> {
+        let dirty_precedents = |cell: &SheetAddress| -> Vec<SheetAddress> {
+            self.precedents(cell).into_iter().filter(|precedent| dirty.contains(precedent)).collect()
+        };
+
+        let mut cells: Vec<SheetAddress> = dirty.iter().copied().collect();
+        cells.sort_by_key(|(sheet, adr)| (*sheet, adr.1, adr.0));
+
+        let mut indices: HashMap<SheetAddress, usize> = HashMap::new();
+        let mut low_links: HashMap<SheetAddress, usize> = HashMap::new();
+        let mut component_stack: Vec<SheetAddress> = Vec::new();
+        let mut on_component_stack: HashSet<SheetAddress> = HashSet::new();
+        let mut order: Vec<Vec<SheetAddress>> = Vec::new();
+
+        for cell in cells {
+            if indices.contains_key(&cell) {
+                continue;
+            }
+
+            // Explicit stack rather than recursion so that long chains of references cannot
+            // overflow the call stack
+            let mut stack: Vec<(SheetAddress, Vec<SheetAddress>)> = Vec::new();
+            indices.insert(cell, indices.len());
+            low_links.insert(cell, indices[&cell]);
+            component_stack.push(cell);
+            on_component_stack.insert(cell);
+            stack.push((cell, dirty_precedents(&cell)));
+
+            while let Some((adr, precedents)) = stack.last_mut() {
+                let adr = *adr;
+                match precedents.pop() {
+                    Some(precedent) => {
+                        if !indices.contains_key(&precedent) {
+                            indices.insert(precedent, indices.len());
+                            low_links.insert(precedent, indices[&precedent]);
+                            component_stack.push(precedent);
+                            on_component_stack.insert(precedent);
+                            stack.push((precedent, dirty_precedents(&precedent)));
+                        } else if on_component_stack.contains(&precedent) {
+                            let low_link = low_links[&adr].min(indices[&precedent]);
+                            low_links.insert(adr, low_link);
+                        }
+                    }
+                    None => {
+                        stack.pop();
+                        if let Some((parent, _)) = stack.last() {
+                            let low_link = low_links[parent].min(low_links[&adr]);
+                            low_links.insert(*parent, low_link);
+                        }
+
+                        if low_links[&adr] == indices[&adr] {
+                            let mut component: Vec<SheetAddress> = Vec::new();
+                            while let Some(member) = component_stack.pop() {
+                                on_component_stack.remove(&member);
+                                component.push(member);
+                                if member == adr {
+                                    break;
+                                }
+                            }
+                            order.push(component);
+                        }
+                    }
+                }
+            }
+        }
+
+        order
+    }
+
+    /// Shortest chain of references from the given cell back to itself
+    fn cycle_path(&self, cell: &SheetAddress, component: &[SheetAddress]) -> Vec<SheetAddress> {
+        let mut previous: HashMap<SheetAddress, SheetAddress> = HashMap::new();
+        let mut queue: VecDeque<SheetAddress> = VecDeque::from([*cell]);
+
+        while let Some(current) = queue.pop_front() {
+            for precedent in self.precedents(&current) {
+                if !component.contains(&precedent) || previous.contains_key(&precedent) {
+                    continue;
+                }
+                previous.insert(precedent, current);
+                if precedent == *cell {
+                    let mut path = vec![*cell];
+                    let mut step = current;
+                    while step != *cell {
+                        path.push(step);
+                        step = previous[&step];
+                    }
+                    path.push(*cell);
+                    path.reverse();
+                    return path;
+                }
+                queue.push_back(precedent);
+            }
+        }
+
+        vec![*cell]
+    }
+
+    /// Non-empty cells read by the formula in the given cell, and the cells spilling into them
+    fn precedents(&self, cell: &SheetAddress) -> Vec<SheetAddress> {
+        match self.references.get(cell) {
+            Some(ranges) => {
+                // Large ranges are looked up by going through the cells with contents instead
+                let mut precedents: Vec<SheetAddress> = Vec::new();
+                for (sheet, range) in ranges {
+                    if range.size() <= self.map.len() as i64 {
+                        precedents.extend(range.cells().map(|adr| (*sheet, adr)).filter(|cell| self.map.contains_key(cell)));
+                    } else {
+                        precedents.extend(self.map.keys().filter(|(other, adr)| other == sheet && range.contains(adr)));
+                    }
+                }
+                let mut seen: HashSet<SheetAddress> = HashSet::new();
+                precedents.retain(|cell| seen.insert(*cell));
+                for (anchor, spill) in &self.spills {
+                    if !spill.blocked && !precedents.contains(anchor) && ranges.iter().any(|(range_sheet, range)| *range_sheet == anchor.0 && range.intersects(&spill.area)) {
+                        precedents.push(*anchor);
+                    }
+                }
+                precedents
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Formula cells that read the given cell, or any of the cells it spills into
+    fn dependents(&self, cell: &SheetAddress) -> Vec<SheetAddress> {
+        let area = match self.spills.get(cell) {
+            Some(spill) if !spill.blocked => spill.area,
+            _ => CellRange(cell.1, cell.1),
+        };
+        match self.readers.get(&cell.0) {
+            Some(readers) => readers.reading(area).into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn get_cell_value(&self, sheet: SheetId, adr: CellAddress) -> Option<&Result<Primitive, CellError>> {
+        self.grids.get(&sheet)?.get_cell(&adr)
+    }
+
+    fn evaluate_cell(&mut self, cell: SheetAddress) {
+        let result = match self.expressions.get(&cell) {
+            Some(Ok(expression)) => expression.evaluate(&Environment::for_sheet(&self.grids, cell.0, &self.functions, &self.names)),
+            Some(Err(err)) => Err(err.clone()),
+            None => {
+                // An empty cell can still hold part of an array result
+                if !self.spilled_into(&cell) {
+                    self.grid_mut(cell.0).remove_cell(&cell.1);
+                }
+                return;
+            }
+        };
+
+        let result = match result {
+            Ok(Primitive::Array(rows)) => self.spill(cell, rows),
+            result => result,
+        };
+        self.grid_mut(cell.0).set_cell(&cell.1, result);
+    }
+
+    /// Values of every cell of the given sheet
+    pub fn get_all_cell_values(&self, sheet: SheetId) -> Vec<(&CellAddress, &Result<Primitive, CellError>)> {
+        match self.grids.get(&sheet) {
+            Some(grid) => grid.get_all_cells(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Cells other than the formula cell that a spill wrote values into
+fn spill_cells(sheet: SheetId, spill: Option<Spill>) -> Vec<SheetAddress> {
+    match spill {
+        Some(spill) if !spill.blocked => area_cells(&spill.area).into_iter().skip(1).map(|adr| (sheet, adr)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Cells in a range, row by row
+fn area_cells(range: &CellRange) -> Vec<CellAddress> {
+    let mut cells: Vec<CellAddress> = Vec::new();
+
+    for y in range.0.1..=range.1.1 {
+        for x in range.0.0..=range.1.0 {
+            cells.push(CellAddress(x, y));
+        }
+    }
+
+    cells
+}
+
+/// Whether the text is a single identifier, as names of sheets and formulas must be
+fn is_identifier(text: &str) -> bool {
+    match lex(text) {
+        Ok(tokens) => tokens.len() == 1 && tokens[0].token_type == TokenType::Identifier,
+        Err(_) => false,
+    }
+}
+
+/// Parses the text of a cell as a formula after '=', a string after ' or a literal value
+fn parse_cell_text(text: &str, functions: &FunctionRegistry, ranges: &NamedRanges, sheets: &SheetNames) -> Option<Result<Box<dyn Evaluatable>, CellError>> {
+    if let Some(formula) = text.strip_prefix('=') {
+        Some(parse_from_string(formula, functions, ranges, sheets))
+    } else if let Some(text) = text.strip_prefix('\'') {
+        Some(Ok(Box::new(Primitive::String(text.to_string()))))
+    } else if let Ok(val) = text.parse::<i64>() {
+        Some(Ok(Box::new(Primitive::Integer(val))))
+    } else if let Ok(val) = text.parse::<bool>() {
+        Some(Ok(Box::new(Primitive::Boolean(val))))
+    } else if let Ok(val) = text.parse::<f64>() {
+        Some(Ok(Box::new(Primitive::Float(val))))
+    } else if !text.is_empty() {
+        Some(Ok(Box::new(Primitive::String(text.to_string()))))
+    } else {
+        None
+    }
+}
+
+/// Text a cell can be set to for it to hold the given value
+pub fn literal_text(val: &Primitive) -> Option<String> {
+    match val {
+        Primitive::Integer(val) => Some(val.to_string()),
+        Primitive::Float(val) => Some(format!("{val:?}")),
+        Primitive::Boolean(val) => Some(val.to_string()),
+        Primitive::String(val) if val.is_empty() || val.starts_with(['=', '\''])
+            || val.parse::<i64>().is_ok() || val.parse::<bool>().is_ok() || val.parse::<f64>().is_ok() => Some(format!("'{val}")),
+        Primitive::String(val) => Some(val.clone()),
+        Primitive::Empty => Some(String::new()),
+        Primitive::Array(_) | Primitive::Lambda(_) => None,
+    }
+}
+
+/// Cell address or range written in the text of a formula, such as Inputs![0, 0]:[0, 9]
+struct WrittenReference<'a> {
+    /// Name of the sheet written before the reference
+    sheet: Option<&'a str>,
+    /// Cells referenced, a single cell being a range of one cell
+    range: CellRange,
+    is_range: bool,
+    /// Whether each corner of the range is written in A1 form, such as B7, rather than as [x, y]
+    a1: (bool, bool),
+    /// Which of the columns and rows of each corner are absolute
+    absolute: (Absolute, Absolute),
+    /// Whether the reference follows a colon, as the end of a range starting at a name
+    after_colon: bool,
+    /// Position of the first character, including the name of the sheet
+    start: usize,
+    /// Position of the first character after the name of the sheet
+    address_start: usize,
+    /// Position just after the last character
+    end: usize,
+}
+
+impl WrittenReference<'_> {
+    /// Text of the given cells written the same way as this reference
+    fn written_as(&self, range: CellRange) -> String {
+        let write = |adr: CellAddress, a1: bool, absolute: Absolute| match a1 {
+            true => adr.to_anchored_a1(absolute),
+            false => format!("[{}, {}]", adr.0, adr.1),
+        };
+
+        match self.is_range {
+            true => format!("{}:{}", write(range.0, self.a1.0, self.absolute.0), write(range.1, self.a1.1, self.absolute.1)),
+            false => write(range.0, self.a1.0, self.absolute.0),
+        }
+    }
+}
+
+/// Cell addresses and ranges written in the tokens of a formula, in order
+fn written_references<'a>(tokens: &[Token<'a>]) -> Vec<WrittenReference<'a>> {
+    // Cell address written as [x, y] or in A1 form starting at the given token, along with which
+    // of its column and row are absolute and the number of tokens it is written with
+    let address_at = |index: usize| -> Option<(CellAddress, Absolute, usize)> {
+        if let Some(token) = tokens.get(index).filter(|token| token.token_type == TokenType::A1Reference) {
+            let (adr, absolute) = CellAddress::from_a1(token.text)?;
+            return Some((adr, absolute, 1));
+        }
+        match tokens.get(index..index + 5)? {
+            [open, x, comma, y, close] if open.token_type == TokenType::OpenBracket && x.token_type == TokenType::IntegerLiteral
+                && comma.token_type == TokenType::Comma && y.token_type == TokenType::IntegerLiteral
+                && close.token_type == TokenType::CloseBracket => Some((CellAddress(x.text.parse().ok()?, y.text.parse().ok()?), Absolute::default(), 5)),
+            _ => None,
+        }
+    };
+    let token_type = |index: Option<usize>| index.and_then(|index| tokens.get(index)).map(|token| token.token_type);
+
+    let mut references: Vec<WrittenReference> = Vec::new();
+    let mut index = 0;
+    while index < tokens.len() {
+        let Some((left, left_absolute, left_length)) = address_at(index) else {
+            index += 1;
+            continue;
+        };
+
+        let has_sheet = token_type(index.checked_sub(1)) == Some(TokenType::LogicalNot) && token_type(index.checked_sub(2)) == Some(TokenType::Identifier);
+        let first = if has_sheet { index - 2 } else { index };
+        let after_colon = token_type(first.checked_sub(1)) == Some(TokenType::Colon);
+        let (range, right_absolute, length, right_length) = match (token_type(Some(index + left_length)), address_at(index + left_length + 1)) {
+            (Some(TokenType::Colon), Some((right, right_absolute, right_length))) => {
+                (CellRange(left, right), right_absolute, left_length + 1 + right_length, right_length)
+            }
+            _ => (CellRange(left, left), left_absolute, left_length, left_length),
+        };
+
+        references.push(WrittenReference {
+            sheet: if has_sheet { Some(tokens[index - 2].text) } else { None },
+            range,
+            is_range: length > left_length,
+            a1: (left_length == 1, right_length == 1),
+            absolute: (left_absolute, right_absolute),
+            after_colon,
+            start: tokens[first].start,
+            address_start: tokens[index].start,
+            end: tokens[index + length - 1].end + 1,
+        });
+        index += length;
+    }
+
+    references
+}
+
+/// Formula with the given spans of text replaced, in order and not overlapping
+fn replace_spans(formula: &str, replacements: Vec<(usize, usize, String)>) -> String {
+    let mut replaced = String::new();
+    let mut copied = 0;
+
+    for (start, end, replacement) in replacements {
+        replaced.push_str(&formula[copied..start]);
+        replaced.push_str(&replacement);
+        copied = end;
+    }
+
+    replaced.push_str(&formula[copied..]);
+    replaced
+}
+
+/// Formula with the references to the shifted sheet following the cells they point to
+fn shift_formula(formula: &str, formula_sheet: Option<SheetId>, shift: &Shift, sheets: &SheetNames) -> String {
+    let Ok(tokens) = lex(formula) else {
+        return formula.to_string();
+    };
+
+    let mut replacements: Vec<(usize, usize, String)> = Vec::new();
+    for reference in written_references(&tokens) {
+        let sheet = match reference.sheet {
+            Some(name) => sheets.get(&name.to_lowercase()).copied(),
+            None => formula_sheet,
+        };
+        if sheet != Some(shift.sheet) {
+            continue;
+        }
+
+        let moved = match reference.is_range {
+            true => shift.range(reference.range),
+            false => shift.address(reference.range.0).map(|adr| CellRange(adr, adr)),
+        };
+        match moved {
+            Some(moved) if moved == reference.range => (),
+            Some(moved) => replacements.push((reference.address_start, reference.end, reference.written_as(moved))),
+            None => replacements.push((reference.start, reference.end, String::from("#REF!"))),
+        }
+    }
+
+    replace_spans(formula, replacements)
+}
+
+/// Block of cells moved to another place, on the same or another sheet
+struct Move {
+    sheet: SheetId,
+    range: CellRange,
+    to_sheet: SheetId,
+    /// Columns and rows the cells move by
+    offset: (i32, i32),
+}
+
+/// Formula with the references to a moved block of cells following them
+fn move_formula(formula: &str, cell: Option<SheetAddress>, moved: &Move, sheets: &SheetNames, sheet_names: &HashMap<SheetId, String>) -> String {
+    let Ok(tokens) = lex(formula) else {
+        return formula.to_string();
+    };
+
+    let formula_sheet = cell.map(|cell| cell.0);
+    let new_formula_sheet = cell.map(|(sheet, adr)| if sheet == moved.sheet && moved.range.contains(&adr) { moved.to_sheet } else { sheet });
+    let mut replacements: Vec<(usize, usize, String)> = Vec::new();
+    for reference in written_references(&tokens) {
+        let sheet = match reference.sheet {
+            Some(name) => sheets.get(&name.to_lowercase()).copied(),
+            None => formula_sheet,
+        };
+        let Some(sheet) = sheet.filter(|_| !reference.after_colon) else {
+            continue;
+        };
+
+        let (target_sheet, range) = if sheet == moved.sheet && moved.range.contains(&reference.range.0) && moved.range.contains(&reference.range.1) {
+            let destination = |adr: CellAddress| CellAddress(adr.0 + moved.offset.0, adr.1 + moved.offset.1);
+            (moved.to_sheet, CellRange(destination(reference.range.0), destination(reference.range.1)))
+        } else {
+            (sheet, reference.range)
+        };
+        if (target_sheet, range) == (sheet, reference.range) && (reference.sheet.is_some() || new_formula_sheet == Some(sheet)) {
+            continue;
+        }
+
+        let sheet_name = match (reference.sheet, new_formula_sheet == Some(target_sheet)) {
+            (None, true) => String::new(),
+            _ => format!("{}!", sheet_names[&target_sheet]),
+        };
+        replacements.push((reference.start, reference.end, format!("{sheet_name}{}", reference.written_as(range))));
+    }
+
+    replace_spans(formula, replacements)
+}
+
+/// Formula with the given sheet named before every reference that names none
+fn qualify_formula(formula: &str, sheet_name: &str) -> String {
+    let Ok(tokens) = lex(formula) else {
+        return formula.to_string();
+    };
+
+    let replacements: Vec<(usize, usize, String)> = written_references(&tokens).into_iter()
+        .filter(|reference| reference.sheet.is_none() && !reference.after_colon)
+        .map(|reference| (reference.start, reference.start, format!("{sheet_name}!")))
+        .collect();
+    replace_spans(formula, replacements)
+}
+
+/// Formula with the sheet prefix of references renamed
+fn rename_sheet_references(formula: &str, old_name: &str, new_name: &str) -> String {
+    let Ok(tokens) = lex(formula) else {
+        return formula.to_string();
+    };
+
+    let replacements: Vec<(usize, usize, String)> = written_references(&tokens).into_iter()
+        .filter(|reference| reference.sheet.is_some_and(|name| name.eq_ignore_ascii_case(old_name)))
+        .map(|reference| (reference.start, reference.address_start, format!("{new_name}!")))
+        .collect();
+    replace_spans(formula, replacements)
+}
+
+/// Formula with every cell reference to the given sheet replaced by #REF!
+fn delete_sheet_references(formula: &str, sheet_name: &str) -> String {
+    let Ok(tokens) = lex(formula) else {
+        return formula.to_string();
+    };
+
+    let replacements: Vec<(usize, usize, String)> = written_references(&tokens).into_iter()
+        .filter(|reference| reference.sheet.is_some_and(|name| name.eq_ignore_ascii_case(sheet_name)))
+        .map(|reference| (reference.start, reference.end, String::from("#REF!")))
+        .collect();
+    replace_spans(formula, replacements)
+}